@@ -0,0 +1,116 @@
+//! Compares the hash rate of the original string-based hashing, kept below
+//! as a reference copy, and of rehashing the whole block through
+//! `hash_block`, against the midstate path `add` mines with. Run with
+//! `cargo bench`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use base64::prelude::*;
+use pz1::{pow, Address, Block, Blockchain, OutPoint, SigningKey, Transaction};
+use sha2::{Digest, Sha256};
+
+const ATTEMPTS: u64 = 1_000_000;
+const DIFFICULTY: u32 = 16;
+
+/// Leading zero characters the original check wanted, about as hard as
+/// [`DIFFICULTY`] leading zero bits.
+const BASE64_DIFFICULTY: usize = 3;
+
+fn main() -> anyhow::Result<()> {
+    let (alice, bob) = (
+        SigningKey::from_bytes(&[1; 32]),
+        SigningKey::from_bytes(&[2; 32]),
+    );
+    let mut blockchain = Blockchain::new(1).with_miner(Address::of(&alice));
+    blockchain.add(Vec::new())?;
+    for nonce in 0..2 {
+        // Each transfer spends the reward for the block before it.
+        let reward = &blockchain.tip().unwrap().transactions()[0];
+        let inputs = vec![OutPoint::new(reward, 0)];
+        blockchain.add(vec![Transaction {
+            sender: Address::of(&alice),
+            recipient: Address::of(&bob),
+            amount: 1,
+            fee: 0,
+            nonce,
+            inputs,
+            memo: "bench".into(),
+            signature: None,
+        }
+        .sign(&alice)])?;
+    }
+    let block = blockchain.tip().unwrap();
+
+    let original = measure(|| {
+        for nonce in 0..ATTEMPTS {
+            let hash = original_hash_block(black_box(block), black_box(nonce));
+            black_box(original_check_hash(&hash, BASE64_DIFFICULTY));
+        }
+    });
+    let legacy = measure(|| {
+        for _ in 0..ATTEMPTS {
+            black_box(blockchain.hash_block(black_box(block)));
+        }
+    });
+    let midstate = measure(|| {
+        let target = pow::Target::from_leading_zeros(DIFFICULTY);
+        let midstate = pow::Midstate::new(black_box(block));
+        for nonce in 0..ATTEMPTS {
+            let digest = midstate.digest(black_box(nonce));
+            black_box(target.is_met_by(&digest));
+        }
+    });
+
+    report("string hash + check_hash", original);
+    report("hash_block", legacy);
+    report("midstate + target", midstate);
+    println!(
+        "speedup: {:.2}x over the original, {:.2}x over hash_block",
+        original.as_secs_f64() / midstate.as_secs_f64(),
+        legacy.as_secs_f64() / midstate.as_secs_f64()
+    );
+    Ok(())
+}
+
+/// The hashing `add` mined with before the midstate: the whole block fed to
+/// a fresh hasher per attempt, the timestamp as a decimal string, and the
+/// digest base64-encoded.
+fn original_hash_block(block: &Block, nonce: u64) -> String {
+    let mut hasher = Sha256::new();
+    block
+        .prev_hash()
+        .inspect(|hash| hasher.update(hash.as_bytes()));
+    hasher.update(
+        block
+            .timestamp()
+            .timestamp_nanos_opt()
+            .unwrap_or_default()
+            .to_string(),
+    );
+    block
+        .transactions()
+        .iter()
+        .for_each(|transaction| hasher.update(transaction.to_bytes()));
+    hasher.update(nonce.to_le_bytes());
+    BASE64_STANDARD.encode(hasher.finalize())
+}
+
+/// The original difficulty check, on the encoded hash.
+fn original_check_hash(hash: &str, difficulty: usize) -> bool {
+    hash.chars().take(difficulty).all(|c| c == '0')
+}
+
+fn measure(f: impl Fn()) -> Duration {
+    f();
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let rate = ATTEMPTS as f64 / elapsed.as_secs_f64() / 1e6;
+    println!("{name:<28} {rate:>8.3} MH/s ({elapsed:?} for {ATTEMPTS} attempts)");
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use crate::{Address, Blockchain, Transaction};
+
+/// What the chain records for an address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    /// Transactions the address has sent, which is the nonce its next one
+    /// must carry.
+    pub nonce: u64,
+}
+
+/// Why [`Accounts::apply`] rejected a transaction.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum AccountError {
+    #[error("{address} has {balance}, short of the {needed} it pays out")]
+    Overdraw {
+        address: Address,
+        balance: u64,
+        needed: u64,
+    },
+    #[error("{address} must use nonce {expected}, not {actual}")]
+    BadNonce {
+        address: Address,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("balance of {0} overflows")]
+    Overflow(Address),
+}
+
+/// Balances and nonces by address as of some block, checked whichever
+/// [`StateModel`](crate::StateModel) the chain follows. Under UTXOs they
+/// follow the same transfers as [`UtxoSet`](crate::UtxoSet), kept per
+/// address instead of per output.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Accounts {
+    accounts: HashMap<Address, Account>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An address no transaction has touched has a zero balance and nonce.
+    pub fn get(&self, address: &Address) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    /// Every address a transaction has touched, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Address, &Account)> {
+        self.accounts.iter()
+    }
+
+    /// Moves `amount` from the sender to the recipient, takes `fee` from the
+    /// sender too and counts the sender's nonce up, or changes nothing if
+    /// [`Accounts::check`] rejects it.
+    ///
+    /// A coinbase only credits its recipient.
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<(), AccountError> {
+        self.check(transaction)?;
+        if transaction.is_coinbase() {
+            return self.credit(transaction.recipient, transaction.amount);
+        }
+        let sender = self.get(&transaction.sender);
+        self.accounts.insert(
+            transaction.sender,
+            Account {
+                balance: sender.balance - transaction.amount - transaction.fee,
+                nonce: sender.nonce + 1,
+            },
+        );
+        self.credit(transaction.recipient, transaction.amount)
+    }
+
+    /// Whether [`Accounts::apply`] would accept the transaction: the sender
+    /// can pay its amount and fee, its nonce is the sender's next and no
+    /// balance overflows.
+    pub fn check(&self, transaction: &Transaction) -> Result<(), AccountError> {
+        let recipient = self.get(&transaction.recipient);
+        if transaction.is_coinbase() {
+            recipient
+                .balance
+                .checked_add(transaction.amount)
+                .ok_or(AccountError::Overflow(transaction.recipient))?;
+            return Ok(());
+        }
+        let sender = self.get(&transaction.sender);
+        if transaction.nonce != sender.nonce {
+            return Err(AccountError::BadNonce {
+                address: transaction.sender,
+                expected: sender.nonce,
+                actual: transaction.nonce,
+            });
+        }
+        let needed = transaction
+            .amount
+            .checked_add(transaction.fee)
+            .ok_or(AccountError::Overflow(transaction.sender))?;
+        if sender.balance < needed {
+            return Err(AccountError::Overdraw {
+                address: transaction.sender,
+                balance: sender.balance,
+                needed,
+            });
+        }
+        recipient
+            .balance
+            .checked_add(transaction.amount)
+            .ok_or(AccountError::Overflow(transaction.recipient))?;
+        Ok(())
+    }
+
+    /// The address's account, if a transaction has touched it.
+    pub(crate) fn touched(&self, address: &Address) -> Option<Account> {
+        self.accounts.get(address).copied()
+    }
+
+    /// Puts back an account as [`Accounts::touched`] returned it.
+    pub(crate) fn restore(&mut self, address: Address, account: Option<Account>) {
+        match account {
+            Some(account) => self.accounts.insert(address, account),
+            None => self.accounts.remove(&address),
+        };
+    }
+
+    fn credit(&mut self, address: Address, amount: u64) -> Result<(), AccountError> {
+        let account = self.accounts.entry(address).or_default();
+        account.balance = account
+            .balance
+            .checked_add(amount)
+            .ok_or(AccountError::Overflow(address))?;
+        Ok(())
+    }
+}
+
+impl Blockchain {
+    /// Balances and nonces as of the canonical tip.
+    pub fn accounts(&self) -> &Accounts {
+        &self.ledger.accounts
+    }
+
+    /// Balances and nonces as of the canonical block at `height`, rewound
+    /// from the tip.
+    pub fn accounts_at(&self, height: usize) -> Option<Accounts> {
+        self.ledger_at(height).map(|ledger| ledger.accounts)
+    }
+}
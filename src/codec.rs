@@ -0,0 +1,388 @@
+//! Compact binary encoding of blocks, used for the chain file and on the
+//! wire. Each record is framed as
+//!
+//! | field    | encoding                                               |
+//! |----------|--------------------------------------------------------|
+//! | format   | `u8`, currently [`FORMAT_VERSION`]                     |
+//! | length   | `u32` length of the payload                            |
+//! | payload  | `length` bytes                                         |
+//! | checksum | first 4 bytes of SHA-256 over format, length, payload  |
+//!
+//! and a block's payload is
+//!
+//! | field        | encoding                                                |
+//! |--------------|---------------------------------------------------------|
+//! | version      | `u32`                                                   |
+//! | prev         | `u8` 0 for genesis, or 1 followed by the 32 hash bytes  |
+//! | timestamp    | `i64` nanoseconds since the Unix epoch                  |
+//! | bits         | `u32`                                                   |
+//! | nonce        | `u64`                                                   |
+//! | transactions | `u32` count, then each as a `u32` length followed by    |
+//! |              | its canonical encoding ([`Transaction::to_bytes`])      |
+//!
+//! with all integers little-endian.
+//!
+//! An empty genesis block encodes to this golden vector, and decoding it
+//! gives the block back:
+//!
+//! ```
+//! use pz1::{codec::DecodeError, Block};
+//!
+//! let golden = [
+//!     0x03, 0x1d, 0x00, 0x00, 0x00, // format, length
+//!     0x03, 0x00, 0x00, 0x00, // version
+//!     0x00, // no prev
+//!     0x00, 0x00, 0x64, 0xa7, 0xb3, 0xb6, 0xe0, 0x0d, // timestamp
+//!     0xff, 0xff, 0x00, 0x1f, // bits
+//!     0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+//!     0x00, 0x00, 0x00, 0x00, // no transactions
+//!     0xe0, 0x70, 0xc1, 0x0f, // checksum
+//! ];
+//! let (block, rest) = Block::decode(&golden)?;
+//! assert!(rest.is_empty());
+//! assert_eq!(block.timestamp().timestamp(), 1_000_000_000);
+//! assert_eq!((block.bits(), block.nonce()), (0x1f00ffff, 42));
+//! assert_eq!(block.encode(), golden);
+//!
+//! let mut corrupted = golden;
+//! corrupted[30] ^= 1;
+//! assert!(matches!(
+//!     Block::decode(&corrupted),
+//!     Err(DecodeError::ChecksumMismatch { .. })
+//! ));
+//! assert!(matches!(
+//!     Block::decode(&golden[..20]),
+//!     Err(DecodeError::Truncated { field: "payload", .. })
+//! ));
+//! # Ok::<(), DecodeError>(())
+//! ```
+//!
+//! Mined blocks round-trip as well:
+//!
+//! ```
+//! use pz1::{Address, Block, Blockchain, OutPoint, SigningKey, Transaction};
+//!
+//! let (alice, bob) = (SigningKey::from_bytes(&[1; 32]), SigningKey::from_bytes(&[2; 32]));
+//! let mut blockchain = Blockchain::new(4).with_miner(Address::of(&alice));
+//! blockchain.add(Vec::new())?;
+//! let reward = &blockchain.tip().unwrap().transactions()[0];
+//! let inputs = vec![OutPoint::new(reward, 0)];
+//! blockchain.add(vec![Transaction {
+//!     sender: Address::of(&alice),
+//!     recipient: Address::of(&bob),
+//!     amount: 10,
+//!     fee: 1,
+//!     nonce: 0,
+//!     inputs,
+//!     memo: "lunch".into(),
+//!     signature: None,
+//! }
+//! .sign(&alice)])?;
+//! let block = blockchain.tip().unwrap();
+//! let encoded = block.encode();
+//! let (decoded, rest) = Block::decode(&encoded)?;
+//! assert!(rest.is_empty());
+//! assert_eq!(&decoded, block);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::io::{self, Read};
+
+use sha2::{Digest, Sha256};
+
+use crate::{hash::Hex, Block, BlockHash, Transaction};
+
+/// Leads every record, and changes whenever any record's layout does, so
+/// older readers fail with [`DecodeError::UnsupportedVersion`].
+pub const FORMAT_VERSION: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("truncated {field}: needed {needed} bytes, {available} left")]
+    Truncated {
+        field: &'static str,
+        needed: usize,
+        available: usize,
+    },
+    #[error("not a chain file")]
+    BadMagic,
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u8),
+    #[error(
+        "checksum mismatch: record says {}, contents hash to {}",
+        Hex(expected),
+        Hex(actual)
+    )]
+    ChecksumMismatch { expected: [u8; 4], actual: [u8; 4] },
+    #[error("invalid {field} flag {value}")]
+    InvalidFlag { field: &'static str, value: u8 },
+    #[error("{len} trailing bytes after {field}")]
+    TrailingBytes { field: &'static str, len: usize },
+    #[error("{field} is not valid UTF-8")]
+    InvalidUtf8 { field: &'static str },
+    #[error("invalid transaction {index}: {error}")]
+    InvalidTransaction {
+        index: usize,
+        #[source]
+        error: Box<DecodeError>,
+    },
+    #[error("{len} byte record exceeds the {max} byte limit")]
+    TooLarge { len: usize, max: usize },
+}
+
+/// Frames `payload` as a checksummed record.
+pub fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = vec![FORMAT_VERSION];
+    record.extend((payload.len() as u32).to_le_bytes());
+    record.extend(payload);
+    let checksum = checksum(&record);
+    record.extend(checksum);
+    record
+}
+
+/// Splits the first record off `bytes`, returning its payload and whatever
+/// follows it.
+pub fn decode_record(bytes: &[u8]) -> Result<(&[u8], &[u8]), DecodeError> {
+    let mut reader = Reader(bytes);
+    let version = reader.u8("format version")?;
+    if version != FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let len = reader.u32("length")? as usize;
+    let payload = reader.take("payload", len)?;
+    let framed = &bytes[..bytes.len() - reader.0.len()];
+    let expected = reader.array("checksum")?;
+    let actual = checksum(framed);
+    if expected != actual {
+        return Err(DecodeError::ChecksumMismatch { expected, actual });
+    }
+    Ok((payload, reader.0))
+}
+
+/// Reads one record off a stream, returning its payload. A record claiming
+/// more than `max_len` payload bytes is rejected before reading any of them;
+/// malformed records fail with [`io::ErrorKind::InvalidData`] wrapping a
+/// [`DecodeError`].
+pub fn read_record(reader: &mut impl Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let invalid = |error| io::Error::new(io::ErrorKind::InvalidData, error);
+    let mut record = vec![0; 5];
+    reader.read_exact(&mut record)?;
+    if record[0] != FORMAT_VERSION {
+        return Err(invalid(DecodeError::UnsupportedVersion(record[0])));
+    }
+    let len = u32::from_le_bytes(record[1..].try_into().expect("4-byte length")) as usize;
+    if len > max_len {
+        return Err(invalid(DecodeError::TooLarge { len, max: max_len }));
+    }
+    record.resize(record.len() + len + 4, 0);
+    reader.read_exact(&mut record[5..])?;
+    let (payload, _) = decode_record(&record).map_err(invalid)?;
+    Ok(payload.to_vec())
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(bytes);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+impl Block {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend(self.version.to_le_bytes());
+        match self.prev {
+            Some(prev) => {
+                payload.push(1);
+                payload.extend(prev.0);
+            }
+            None => payload.push(0),
+        }
+        payload.extend(self.timestamp.to_le_bytes());
+        payload.extend(self.bits.to_le_bytes());
+        payload.extend(self.nonce.to_le_bytes());
+        payload.extend((self.transactions.len() as u32).to_le_bytes());
+        for transaction in &self.transactions {
+            let bytes = transaction.to_bytes();
+            payload.extend((bytes.len() as u32).to_le_bytes());
+            payload.extend(bytes);
+        }
+        encode_record(&payload)
+    }
+
+    /// Decodes the block record at the start of `bytes`, returning the bytes
+    /// after it.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (payload, rest) = decode_record(bytes)?;
+        let mut reader = Reader(payload);
+        let block = Self {
+            version: reader.u32("version")?,
+            prev: match reader.u8("prev flag")? {
+                0 => None,
+                1 => Some(BlockHash(reader.array("prev")?)),
+                value => {
+                    return Err(DecodeError::InvalidFlag {
+                        field: "prev",
+                        value,
+                    })
+                }
+            },
+            timestamp: i64::from_le_bytes(reader.array("timestamp")?),
+            bits: reader.u32("bits")?,
+            nonce: u64::from_le_bytes(reader.array("nonce")?),
+            transactions: (0..reader.u32("transaction count")?)
+                .map(|index| {
+                    let len = reader.u32("transaction length")? as usize;
+                    Transaction::from_bytes(reader.take("transaction", len)?).map_err(|error| {
+                        DecodeError::InvalidTransaction {
+                            index: index as usize,
+                            error: Box::new(error),
+                        }
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        if !reader.0.is_empty() {
+            return Err(DecodeError::TrailingBytes {
+                field: "block",
+                len: reader.0.len(),
+            });
+        }
+        Ok((block, rest))
+    }
+}
+
+pub(crate) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, field: &'static str, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.0.len() < len {
+            return Err(DecodeError::Truncated {
+                field,
+                needed: len,
+                available: self.0.len(),
+            });
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub fn array<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], DecodeError> {
+        Ok(self.take(field, N)?.try_into().expect("took N bytes"))
+    }
+
+    pub fn u8(&mut self, field: &'static str) -> Result<u8, DecodeError> {
+        self.array(field).map(|[byte]| byte)
+    }
+
+    pub fn u32(&mut self, field: &'static str) -> Result<u32, DecodeError> {
+        self.array(field).map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self, field: &'static str) -> Result<u64, DecodeError> {
+        self.array(field).map(u64::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, OutPoint, SigningKey, BLOCK_VERSION};
+
+    fn transaction() -> Transaction {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        Transaction {
+            sender: Address::of(&key),
+            recipient: Address::COINBASE,
+            amount: 1,
+            fee: 0,
+            nonce: 0,
+            inputs: vec![OutPoint {
+                tx: [7; 32],
+                index: 1,
+            }],
+            memo: "memo".into(),
+            signature: None,
+        }
+        .sign(&key)
+    }
+
+    #[test]
+    fn transaction_round_trips() {
+        let transaction = transaction();
+        assert_eq!(
+            Transaction::from_bytes(&transaction.to_bytes()),
+            Ok(transaction)
+        );
+    }
+
+    #[test]
+    fn transaction_errors_name_the_field() {
+        let bytes = transaction().to_bytes();
+        assert!(matches!(
+            Transaction::from_bytes(&bytes[..85]),
+            Err(DecodeError::Truncated { field: "nonce", .. })
+        ));
+        assert!(matches!(
+            Transaction::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated {
+                field: "signature",
+                ..
+            })
+        ));
+
+        let mut bad_flag = bytes.clone();
+        bad_flag[bytes.len() - 65] = 2;
+        assert_eq!(
+            Transaction::from_bytes(&bad_flag),
+            Err(DecodeError::InvalidFlag {
+                field: "signature",
+                value: 2
+            })
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Transaction::from_bytes(&trailing),
+            Err(DecodeError::TrailingBytes {
+                field: "transaction",
+                len: 1
+            })
+        );
+
+        let memo = bytes.len() - 65 - 4;
+        let mut bad_memo = bytes;
+        bad_memo[memo] = 0xff;
+        assert_eq!(
+            Transaction::from_bytes(&bad_memo),
+            Err(DecodeError::InvalidUtf8 { field: "memo" })
+        );
+    }
+
+    #[test]
+    fn block_names_the_bad_transaction() {
+        let mut payload = Vec::new();
+        payload.extend(BLOCK_VERSION.to_le_bytes());
+        payload.push(0);
+        payload.extend(0i64.to_le_bytes());
+        payload.extend(0u32.to_le_bytes());
+        payload.extend(0u64.to_le_bytes());
+        payload.extend(2u32.to_le_bytes());
+        for bytes in [transaction().to_bytes(), vec![0; 10]] {
+            payload.extend((bytes.len() as u32).to_le_bytes());
+            payload.extend(bytes);
+        }
+        let error = Block::decode(&encode_record(&payload)).unwrap_err();
+        assert_eq!(
+            error,
+            DecodeError::InvalidTransaction {
+                index: 1,
+                error: Box::new(DecodeError::Truncated {
+                    field: "sender",
+                    needed: 32,
+                    available: 10
+                }),
+            }
+        );
+    }
+}
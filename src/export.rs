@@ -0,0 +1,141 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    pow::Retarget,
+    storage::{self, ChainParams},
+    Block, BlockHash, Blockchain, StateModel, Subsidy,
+};
+
+/// JSON shape of a whole chain: its difficulty, subsidy and state rules and
+/// every block along with its height and hash. Exports without a state
+/// model follow UTXOs.
+#[derive(Serialize, Deserialize)]
+struct ChainRecord<Blocks> {
+    bits: u32,
+    retarget: Option<Retarget>,
+    subsidy: Subsidy,
+    #[serde(default)]
+    state: StateModel,
+    blocks: Blocks,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockRecord<B> {
+    height: usize,
+    hash: BlockHash,
+    #[serde(flatten)]
+    block: B,
+}
+
+struct Blocks<'a>(&'a Blockchain);
+
+impl Serialize for Blocks<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            self.0
+                .iter()
+                .enumerate()
+                .map(|(height, block)| BlockRecord {
+                    height,
+                    hash: self.0.hashes[height],
+                    block,
+                }),
+        )
+    }
+}
+
+impl Serialize for Blockchain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ChainRecord {
+            bits: self.target.to_compact(),
+            retarget: self.retarget,
+            subsidy: self.subsidy,
+            state: self.ledger.model,
+            blocks: Blocks(self),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Why [`Blockchain::from_json`] rejected an export whose blocks don't match
+/// the heights and hashes stored alongside them.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ImportError {
+    #[error("block {height} claims height {stored}")]
+    Height { height: usize, stored: usize },
+    #[error("block {height} claims hash {claimed} but hashes to {actual}")]
+    Hash {
+        height: usize,
+        claimed: BlockHash,
+        actual: BlockHash,
+    },
+}
+
+/// Rebuilds the chain and rejects it unless the stored heights and hashes
+/// match the blocks and it passes `verify_chain`.
+impl TryFrom<ChainRecord<Vec<BlockRecord<Block>>>> for Blockchain {
+    type Error = anyhow::Error;
+
+    fn try_from(record: ChainRecord<Vec<BlockRecord<Block>>>) -> anyhow::Result<Self> {
+        let mut blockchain = Self::from_params(ChainParams {
+            bits: record.bits,
+            retarget: record.retarget,
+            subsidy: record.subsidy,
+            state: record.state,
+        })?;
+        for (
+            height,
+            BlockRecord {
+                height: stored,
+                hash,
+                block,
+            },
+        ) in record.blocks.into_iter().enumerate()
+        {
+            if stored != height {
+                return Err(ImportError::Height { height, stored }.into());
+            }
+            let actual = blockchain.hash_block(&block);
+            if hash != actual {
+                return Err(ImportError::Hash {
+                    height,
+                    claimed: hash,
+                    actual,
+                }
+                .into());
+            }
+            blockchain.push(block);
+        }
+        blockchain.verify_chain()?;
+        Ok(blockchain)
+    }
+}
+
+impl Blockchain {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Rebuilds a chain from [`Blockchain::to_json`]'s output, failing with
+    /// an [`ImportError`] or [`ValidationError`](crate::ValidationError) in
+    /// the error's chain if the blocks don't check out.
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let record: ChainRecord<Vec<BlockRecord<Block>>> =
+            serde_json::from_str(json).context("invalid chain export")?;
+        Self::try_from(record)
+    }
+
+    /// The chain in the binary format of chain files.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        storage::encode_chain(&self.params(), &self.blocks)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (params, blocks) = storage::decode_chain(bytes)?;
+        let mut blockchain = Self::from_params(params)?;
+        blocks.into_iter().for_each(|block| blockchain.push(block));
+        blockchain.verify_chain()?;
+        Ok(blockchain)
+    }
+}
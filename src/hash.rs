@@ -0,0 +1,103 @@
+use core::{fmt, str::FromStr};
+
+use anyhow::Context;
+use base64::prelude::*;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// SHA-256 of a block's canonical header.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockHash(pub [u8; 32]);
+
+impl BlockHash {
+    pub const ZERO: Self = Self([0; 32]);
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Displays the hash in standard base64 instead of hex.
+    pub fn base64(&self) -> impl fmt::Display + '_ {
+        struct Base64<'a>(&'a BlockHash);
+
+        impl fmt::Display for Base64<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&BASE64_STANDARD.encode(self.0 .0))
+            }
+        }
+
+        Base64(self)
+    }
+}
+
+impl From<[u8; 32]> for BlockHash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Hex(&self.0).fmt(f)
+    }
+}
+
+impl fmt::Debug for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlockHash({self})")
+    }
+}
+
+/// Parses either the 64-digit hex or the 44-character base64 form.
+impl FromStr for BlockHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.len() {
+            64 => parse_hex(s).map(Self),
+            44 => BASE64_STANDARD
+                .decode(s)
+                .context("invalid base64")?
+                .try_into()
+                .map(Self)
+                .map_err(|_| anyhow::anyhow!("expected 32 bytes")),
+            len => anyhow::bail!("expected 64 hex digits or 44 base64 characters, got {len}"),
+        }
+    }
+}
+
+/// Serialized as the hex string it displays as.
+impl Serialize for BlockHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Displays bytes as lowercase hex.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+/// Parses exactly `N` bytes of hex, in either case.
+pub fn parse_hex<const N: usize>(s: &str) -> anyhow::Result<[u8; N]> {
+    if s.len() != N * 2 || !s.is_ascii() {
+        anyhow::bail!("expected {} hex digits, got {s:?}", N * 2);
+    }
+    let mut bytes = [0; N];
+    for (byte, hex) in bytes.iter_mut().zip(s.as_bytes().chunks(2)) {
+        let hex = core::str::from_utf8(hex)?;
+        *byte = u8::from_str_radix(hex, 16).with_context(|| format!("invalid hex {hex:?}"))?;
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{BlockHash, HEADER_DOMAIN};
+
+/// Everything a block hash commits to, with the transactions standing in as
+/// their merkle root, so proof-of-work can be checked without the block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub(crate) version: u32,
+    pub(crate) prev: Option<BlockHash>,
+    /// Nanoseconds since the Unix epoch.
+    pub(crate) timestamp: i64,
+    pub(crate) merkle_root: [u8; 32],
+    pub(crate) bits: u32,
+    pub(crate) nonce: u64,
+}
+
+impl Header {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn prev_hash(&self) -> Option<BlockHash> {
+        self.prev
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_nanos(self.timestamp)
+    }
+
+    pub fn merkle_root(&self) -> [u8; 32] {
+        self.merkle_root
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn hash(&self) -> BlockHash {
+        BlockHash(Sha256::digest(self.bytes()).into())
+    }
+
+    /// The canonical encoding the block hash is the SHA-256 of, with
+    /// integers little-endian:
+    ///
+    /// | field       | encoding                                        |
+    /// |-------------|-------------------------------------------------|
+    /// | domain      | the 16 ASCII bytes `pz1 block header`           |
+    /// | version     | `u32`                                           |
+    /// | prev        | the parent's 32 hash bytes                      |
+    /// | timestamp   | `i64` nanoseconds since the Unix epoch          |
+    /// | merkle root | 32 bytes                                        |
+    /// | bits        | `u32`                                           |
+    /// | nonce       | `u64`                                           |
+    ///
+    /// The genesis block's prev is all zeroes. The nonce comes last so miners
+    /// can hash everything before it once.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.prefix();
+        bytes.extend(self.nonce.to_le_bytes());
+        bytes
+    }
+
+    /// [`Header::bytes`] without the trailing nonce.
+    pub(crate) fn prefix(&self) -> Vec<u8> {
+        let mut bytes = HEADER_DOMAIN.to_vec();
+        bytes.extend(self.version.to_le_bytes());
+        bytes.extend(self.prev.unwrap_or(BlockHash::ZERO).0);
+        bytes.extend(self.timestamp.to_le_bytes());
+        bytes.extend(self.merkle_root);
+        bytes.extend(self.bits.to_le_bytes());
+        bytes
+    }
+}
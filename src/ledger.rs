@@ -0,0 +1,322 @@
+use core::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Account, AccountError, Accounts, Address, Block, BlockHash, Blockchain, Transaction, UtxoError,
+    UtxoSet,
+};
+
+/// How a chain decides whether a sender can pay, fixed when it's created.
+/// Balances and nonces are tracked either way, and every transfer must
+/// carry the sender's next nonce and stay within its balance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateModel {
+    /// Transfers spend unspent outputs, see [`UtxoSet`].
+    #[default]
+    Utxo,
+    /// Transfers debit the sender's balance and need no inputs, so no
+    /// outputs are tracked.
+    Accounts,
+}
+
+impl fmt::Display for StateModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Utxo => "utxo",
+            Self::Accounts => "accounts",
+        })
+    }
+}
+
+impl FromStr for StateModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "utxo" => Ok(Self::Utxo),
+            "accounts" => Ok(Self::Accounts),
+            _ => anyhow::bail!("expected utxo or accounts, not {s:?}"),
+        }
+    }
+}
+
+/// Balances, nonces and unspent outputs as of some block. Under
+/// [`StateModel::Utxo`] both views follow the same transfers, and a
+/// transaction changes neither unless both accept it; under
+/// [`StateModel::Accounts`] no outputs are tracked. What each connected
+/// block changed is kept so it can be disconnected again, tip first.
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    pub(crate) model: StateModel,
+    pub(crate) accounts: Accounts,
+    pub(crate) utxos: UtxoSet,
+    /// The accounts each connected block's transactions touched as they
+    /// were before, lowest block first; the unspent outputs keep their own.
+    undo: Vec<Vec<(Address, Option<Account>)>>,
+}
+
+/// Why [`Ledger::apply`] rejected a transaction.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum LedgerError {
+    #[error(transparent)]
+    Account(#[from] AccountError),
+    #[error(transparent)]
+    Utxo(#[from] UtxoError),
+}
+
+impl Ledger {
+    pub(crate) fn new(model: StateModel) -> Self {
+        Self {
+            model,
+            ..Self::default()
+        }
+    }
+
+    /// Starts recording the changes of the next block's transactions.
+    pub(crate) fn open_block(&mut self) {
+        self.utxos.open_block();
+        self.undo.push(Vec::new());
+    }
+
+    /// Applies the transaction at `index` in the block opened last.
+    pub(crate) fn apply(
+        &mut self,
+        index: usize,
+        transaction: &Transaction,
+    ) -> Result<(), LedgerError> {
+        self.accounts.check(transaction)?;
+        if self.model == StateModel::Utxo {
+            self.utxos.apply(index, transaction)?;
+        }
+        let undo = self.undo.last_mut().expect("applying to an open block");
+        if !transaction.is_coinbase() {
+            undo.push((
+                transaction.sender,
+                self.accounts.touched(&transaction.sender),
+            ));
+        }
+        undo.push((
+            transaction.recipient,
+            self.accounts.touched(&transaction.recipient),
+        ));
+        self.accounts
+            .apply(transaction)
+            .expect("checked before spending");
+        Ok(())
+    }
+
+    /// Applies the block's transactions in order, passing over any rejected
+    /// ones, as those are verification's to report.
+    pub(crate) fn connect(&mut self, block: &Block) {
+        self.open_block();
+        block
+            .transactions
+            .iter()
+            .enumerate()
+            .for_each(|(index, transaction)| {
+                let _ = self.apply(index, transaction);
+            });
+    }
+
+    /// Reverts the most recently connected block, returning whether there
+    /// was one.
+    pub(crate) fn disconnect(&mut self) -> bool {
+        let Some(changes) = self.undo.pop() else {
+            return false;
+        };
+        Self::revert(&mut self.accounts, &changes);
+        self.utxos.disconnect()
+    }
+
+    /// A copy of the state `blocks` blocks below this one, without the
+    /// history to disconnect any further, as validating blocks on top of it
+    /// doesn't need that.
+    ///
+    /// Panics if fewer than `blocks` blocks are connected.
+    pub(crate) fn rewound(&self, blocks: usize) -> Self {
+        assert!(blocks <= self.undo.len(), "rewound past genesis");
+        let mut accounts = self.accounts.clone();
+        self.undo
+            .iter()
+            .rev()
+            .take(blocks)
+            .for_each(|changes| Self::revert(&mut accounts, changes));
+        Self {
+            model: self.model,
+            accounts,
+            utxos: self.utxos.rewound(blocks),
+            undo: Vec::new(),
+        }
+    }
+
+    fn revert(accounts: &mut Accounts, changes: &[(Address, Option<Account>)]) {
+        for &(address, account) in changes.iter().rev() {
+            accounts.restore(address, account);
+        }
+    }
+}
+
+impl Blockchain {
+    /// The ledger as of the canonical block at `height`, rewound from the
+    /// tip.
+    pub(crate) fn ledger_at(&self, height: usize) -> Option<Ledger> {
+        self.blocks.get(height)?;
+        Some(self.ledger.rewound(self.blocks.len() - 1 - height))
+    }
+
+    /// The ledger as of `tip` on any branch, or before genesis if there is
+    /// none. A side branch is connected onto the canonical chain rewound to
+    /// where it forks off.
+    pub(crate) fn ledger_after(&self, tip: Option<BlockHash>) -> Ledger {
+        let Some(mut cursor) = tip else {
+            return Ledger::new(self.ledger.model);
+        };
+        let mut branch = Vec::new();
+        while let Some(fork) = self.forks.get(&cursor) {
+            branch.push(&fork.block);
+            cursor = fork
+                .block
+                .prev
+                .expect("branches fork off the canonical chain");
+        }
+        let mut ledger = self
+            .ledger_at(self.heights[&cursor])
+            .expect("fork point is canonical");
+        branch
+            .into_iter()
+            .rev()
+            .for_each(|block| ledger.connect(block));
+        ledger
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{utxo::Output, OutPoint, SigningKey, ValidationError};
+
+    fn unspent(ledger: &Ledger) -> HashMap<OutPoint, Output> {
+        ledger
+            .utxos
+            .iter()
+            .map(|(outpoint, output)| (*outpoint, *output))
+            .collect()
+    }
+
+    fn transfer(key: &SigningKey, nonce: u64, inputs: Vec<OutPoint>) -> Transaction {
+        Transaction {
+            sender: Address::of(key),
+            recipient: Address::of(&SigningKey::from_bytes(&[2; 32])),
+            amount: 5,
+            fee: 1,
+            nonce,
+            inputs,
+            memo: String::new(),
+            signature: None,
+        }
+        .sign(key)
+    }
+
+    #[test]
+    fn account_model_transfers_need_no_inputs() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let mut blockchain = Blockchain::new(4)
+            .with_miner(Address::of(&key))
+            .with_state_model(StateModel::Accounts);
+        blockchain.add(Vec::new()).unwrap();
+        blockchain.add(vec![transfer(&key, 0, Vec::new())]).unwrap();
+        let recipient = Address::of(&SigningKey::from_bytes(&[2; 32]));
+        assert_eq!(blockchain.accounts().get(&recipient).balance, 5);
+        assert!(blockchain.utxos().is_empty());
+        assert!(blockchain
+            .template(vec![transfer(&key, 0, Vec::new())])
+            .is_err());
+
+        let decoded = Blockchain::from_bytes(&blockchain.to_bytes()).unwrap();
+        assert_eq!(decoded.state_model(), StateModel::Accounts);
+        let json = blockchain.to_json().unwrap();
+        assert!(json.contains(r#""state": "accounts""#));
+        assert_eq!(
+            Blockchain::from_json(&json).unwrap().state_model(),
+            StateModel::Accounts
+        );
+        let as_utxos = json.replace(r#""state": "accounts""#, r#""state": "utxo""#);
+        let error = Blockchain::from_json(&as_utxos).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ValidationError::BadSpend {
+                height: 1,
+                error: UtxoError::NoInputs { index: 1 },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn utxo_model_transfers_need_inputs() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let mut blockchain = Blockchain::new(4).with_miner(Address::of(&key));
+        blockchain.add(Vec::new()).unwrap();
+        assert_eq!(blockchain.state_model(), StateModel::Utxo);
+        assert!(blockchain
+            .template(vec![transfer(&key, 0, Vec::new())])
+            .is_err());
+        let coinbase = &blockchain.tip().unwrap().transactions()[0];
+        let input = OutPoint::new(coinbase, 0);
+        blockchain
+            .add(vec![transfer(&key, 0, vec![input])])
+            .unwrap();
+    }
+
+    #[test]
+    fn disconnecting_and_rewinding_match_replaying() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let sender = Address::of(&key);
+        let mut blockchain = Blockchain::new(4).with_miner(sender);
+        blockchain.add(Vec::new()).unwrap();
+        let coinbase = blockchain.tip().unwrap().transactions()[0].clone();
+        let transaction = Transaction {
+            sender,
+            recipient: Address::of(&SigningKey::from_bytes(&[2; 32])),
+            amount: 1,
+            fee: 1,
+            nonce: 0,
+            inputs: vec![OutPoint::new(&coinbase, 0)],
+            memo: String::new(),
+            signature: None,
+        }
+        .sign(&key);
+        blockchain.add(vec![transaction]).unwrap();
+        blockchain.add(Vec::new()).unwrap();
+
+        let replayed: Vec<_> = (0..blockchain.len())
+            .map(|height| {
+                let mut ledger = Ledger::default();
+                blockchain
+                    .iter()
+                    .take(height + 1)
+                    .for_each(|block| ledger.connect(block));
+                ledger
+            })
+            .collect();
+        for (height, replayed) in replayed.iter().enumerate() {
+            let rewound = blockchain.ledger_at(height).unwrap();
+            assert_eq!(rewound.accounts, replayed.accounts, "height {height}");
+            assert_eq!(unspent(&rewound), unspent(replayed), "height {height}");
+        }
+
+        let mut ledger = blockchain.ledger;
+        for replayed in replayed.iter().rev() {
+            assert_eq!(ledger.accounts, replayed.accounts);
+            assert_eq!(unspent(&ledger), unspent(replayed));
+            assert!(ledger.disconnect());
+        }
+        assert_eq!(ledger.accounts, Accounts::default());
+        assert!(ledger.utxos.is_empty());
+        assert!(!ledger.disconnect());
+    }
+}
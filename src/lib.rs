@@ -0,0 +1,708 @@
+use core::fmt;
+use std::{
+    collections::HashMap,
+    iter,
+    num::NonZeroUsize,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+pub use accounts::{Account, AccountError, Accounts};
+pub use ed25519_dalek::SigningKey;
+pub use export::ImportError;
+pub use hash::BlockHash;
+pub use header::Header;
+pub use ledger::StateModel;
+use ledger::{Ledger, LedgerError};
+pub use mempool::{Mempool, MempoolError};
+pub use merkle::MerkleProof;
+pub use node::Node;
+use pow::{Midstate, Retarget, Target};
+pub use query::BlockRef;
+pub use reward::{CoinbaseError, Subsidy};
+use storage::{ChainParams, Storage};
+pub use transaction::{generate_key, Address, Transaction, TransactionError};
+use tree::Fork;
+pub use tree::{BlockError, Reorg};
+pub use utxo::{OutPoint, UtxoError, UtxoSet};
+pub use validation::{ValidationError, ValidationReport};
+
+pub mod accounts;
+pub mod codec;
+mod export;
+pub mod hash;
+mod header;
+mod ledger;
+pub mod mempool;
+pub mod merkle;
+mod message;
+pub mod node;
+pub mod pow;
+mod query;
+pub mod reward;
+mod storage;
+mod sync;
+mod transaction;
+mod tree;
+pub mod utxo;
+mod validation;
+
+/// Header format version hashed into every block.
+pub const BLOCK_VERSION: u32 = 3;
+
+/// Prefix of every encoded header, so a block hash can't be mistaken for the
+/// hash of any other structure.
+const HEADER_DOMAIN: &[u8] = b"pz1 block header";
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    version: u32,
+    prev: Option<BlockHash>,
+    /// Nanoseconds since the Unix epoch.
+    timestamp: i64,
+    transactions: Vec<Transaction>,
+    bits: u32,
+    nonce: u64,
+}
+
+impl Block {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn prev_hash(&self) -> Option<BlockHash> {
+        self.prev
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_nanos(self.timestamp)
+    }
+
+    /// The part of the block its hash commits to.
+    pub fn header(&self) -> Header {
+        Header {
+            version: self.version,
+            prev: self.prev,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root(),
+            bits: self.bits,
+            nonce: self.nonce,
+        }
+    }
+
+    /// The canonical header encoding, see [`Header::bytes`].
+    pub fn header_bytes(&self) -> Vec<u8> {
+        self.header().bytes()
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// The commitment to `transactions` covered by the block hash.
+    pub fn merkle_root(&self) -> [u8; 32] {
+        merkle::root(&self.transactions)
+    }
+
+    /// Proves the transaction at `index` is part of this block, checkable
+    /// against [`Block::merkle_root`] with [`MerkleProof::verify`].
+    pub fn prove(&self, index: usize) -> Option<MerkleProof> {
+        MerkleProof::new(&self.transactions, index)
+    }
+
+    /// The compact encoding of the target this block was mined against.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// The work this block adds to its branch, or none if its bits don't
+    /// decode.
+    pub fn work(&self) -> u128 {
+        Target::from_compact(self.bits).map_or(0, |target| target.work())
+    }
+
+    /// Sets the nonce to the lowest one whose hash meets the block's bits.
+    /// The nonce space is split between `threads` workers, each taking every
+    /// `threads`-th nonce. A worker gives up once it passes the lowest valid
+    /// nonce found so far, so the result is the same as a sequential search.
+    pub fn mine(mut self, threads: NonZeroUsize) -> Self {
+        let threads = threads.get() as u64;
+        let target = Target::from_compact(self.bits).expect("mining against a valid target");
+        let midstate = Midstate::new(&self);
+        let found = AtomicU64::new(u64::MAX);
+        let search = |start: u64| {
+            let mut nonce = start;
+            while nonce < found.load(Ordering::Relaxed) {
+                if target.is_met_by(&midstate.digest(nonce)) {
+                    found.fetch_min(nonce, Ordering::Relaxed);
+                    return;
+                }
+                match nonce.checked_add(threads) {
+                    Some(next) => nonce = next,
+                    None => return,
+                }
+            }
+        };
+        std::thread::scope(|scope| {
+            (1..threads).for_each(|start| {
+                scope.spawn(move || search(start));
+            });
+            search(0);
+        });
+        self.nonce = found.into_inner();
+        self
+    }
+}
+
+/// Blocks of the canonical chain are kept contiguously in chain order, so the
+/// height of a block is its index, alongside their hashes, the cumulative
+/// work up to each and a reverse index from hash to height. Blocks on
+/// competing branches are kept apart, see [`Blockchain::accept`].
+pub struct Blockchain {
+    blocks: Vec<Block>,
+    hashes: Vec<BlockHash>,
+    work: Vec<u128>,
+    heights: HashMap<BlockHash, usize>,
+    forks: HashMap<BlockHash, Fork>,
+    /// Balances, nonces and unspent outputs as of the canonical tip.
+    ledger: Ledger,
+    target: Target,
+    retarget: Option<Retarget>,
+    subsidy: Subsidy,
+    /// Who the blocks `add` mines pay their reward to.
+    miner: Option<Address>,
+    threads: NonZeroUsize,
+    storage: Option<Storage>,
+}
+
+impl Blockchain {
+    /// Block hashes must start with `difficulty` zero bits, rounded down to
+    /// the precision of the compact target stored in each block. Unless
+    /// retargeting is enabled, this target is used for every block.
+    ///
+    /// Panics if `difficulty` is 256 or more, which no hash could meet.
+    pub fn new(difficulty: u32) -> Self {
+        assert!(
+            difficulty < 256,
+            "difficulty {difficulty} exceeds the hash size"
+        );
+        let target = Target::from_leading_zeros(difficulty).to_compact();
+        Self {
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+            work: Vec::new(),
+            heights: HashMap::new(),
+            forks: HashMap::new(),
+            ledger: Ledger::default(),
+            target: Target::from_compact(target).expect("normalized compact target"),
+            retarget: None,
+            subsidy: Subsidy::default(),
+            miner: None,
+            threads: NonZeroUsize::MIN,
+            storage: None,
+        }
+    }
+
+    /// Sets how many worker threads `add` uses to search for a nonce.
+    pub fn with_mining_threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// How many worker threads `add` uses to search for a nonce.
+    pub fn mining_threads(&self) -> NonZeroUsize {
+        self.threads
+    }
+
+    /// Adjusts the target as blocks are added, see [`Retarget`].
+    ///
+    /// Panics if [`Retarget::validate`] rejects the rules.
+    pub fn with_retarget(mut self, retarget: Retarget) -> Self {
+        if let Err(error) = retarget.validate() {
+            panic!("{error}");
+        }
+        self.retarget = Some(retarget);
+        self
+    }
+
+    /// Sets how many coins blocks may mint, see [`Subsidy`].
+    pub fn with_subsidy(mut self, subsidy: Subsidy) -> Self {
+        assert!(
+            subsidy.halving_interval >= 1,
+            "halving interval must be at least 1"
+        );
+        self.subsidy = subsidy;
+        self
+    }
+
+    /// Sets how the chain decides whether a sender can pay, see
+    /// [`StateModel`]. Only takes effect on a chain without blocks yet.
+    pub fn with_state_model(mut self, model: StateModel) -> Self {
+        if self.blocks.is_empty() {
+            self.ledger = Ledger::new(model);
+        }
+        self
+    }
+
+    pub fn state_model(&self) -> StateModel {
+        self.ledger.model
+    }
+
+    /// Pays the reward of each block `add` mines to `miner`. Without one,
+    /// mined blocks claim nothing.
+    pub fn with_miner(mut self, miner: Address) -> Self {
+        self.miner = Some(miner);
+        self
+    }
+
+    /// Reloads the chain persisted at `path` and appends every block mined
+    /// from now on to it. A new file records this chain's difficulty rules,
+    /// while those recorded in an existing file take precedence over them.
+    pub fn open(self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let blockchain = self.open_unverified(path)?;
+        blockchain
+            .verify_chain()
+            .context("persisted chain failed verification")?;
+        Ok(blockchain)
+    }
+
+    /// Like [`Blockchain::open`], but loads the chain even if it is invalid,
+    /// e.g. to audit it with [`Blockchain::verify_report`].
+    pub fn open_unverified(self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let (storage, params, blocks) = Storage::open(path, self.params())?;
+        let mut blockchain = Self {
+            miner: self.miner,
+            threads: self.threads,
+            ..Self::from_params(params)?
+        };
+        blocks.into_iter().for_each(|block| blockchain.push(block));
+        blockchain.storage = Some(storage);
+        Ok(blockchain)
+    }
+
+    /// Writes the chain to a new file at `path` and appends every block
+    /// mined from now on to it.
+    pub fn save(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.storage = Some(Storage::create(path, self.params(), &self.blocks)?);
+        Ok(())
+    }
+
+    fn params(&self) -> ChainParams {
+        ChainParams {
+            bits: self.target.to_compact(),
+            retarget: self.retarget,
+            subsidy: self.subsidy,
+            state: self.ledger.model,
+        }
+    }
+
+    fn from_params(params: ChainParams) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            params.subsidy.halving_interval >= 1,
+            "invalid subsidy rules: {:?}",
+            params.subsidy
+        );
+        anyhow::ensure!(
+            params.bits & 0x007f_ffff != 0,
+            "chain bits {:#010x} encode a zero target, which no block can meet",
+            params.bits
+        );
+        let blockchain = Self {
+            target: Target::from_compact(params.bits).context("invalid chain bits")?,
+            ..Self::new(0)
+                .with_subsidy(params.subsidy)
+                .with_state_model(params.state)
+        };
+        Ok(match params.retarget {
+            Some(retarget) => {
+                retarget
+                    .validate()
+                    .with_context(|| format!("invalid retarget rules: {retarget:?}"))?;
+                blockchain.with_retarget(retarget)
+            }
+            None => blockchain,
+        })
+    }
+
+    /// Mines a block of `transactions` after a coinbase claiming the full
+    /// reward for it, and appends it.
+    pub fn add(&mut self, transactions: Vec<Transaction>) -> anyhow::Result<()> {
+        let new_block = self.template(transactions)?.mine(self.threads);
+        if let Some(storage) = self.storage.as_mut() {
+            storage.append(&new_block)?;
+        }
+        self.push(new_block);
+        Ok(())
+    }
+
+    /// The next block on the canonical tip, holding `transactions` after a
+    /// coinbase claiming the full reward for it, yet to be mined with
+    /// [`Block::mine`]. Fails if a transaction doesn't apply.
+    pub fn template(&self, transactions: Vec<Transaction>) -> anyhow::Result<Block> {
+        let height = self.blocks.len();
+        let coinbase = match self.miner {
+            Some(miner) => Transaction::coinbase(miner, self.reward(height, &transactions), height),
+            None => Transaction::coinbase(Address::COINBASE, 0, height),
+        };
+        let mut ledger = self.ledger.rewound(0);
+        ledger.open_block();
+        ledger.apply(0, &coinbase)?;
+        transactions
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, transaction)| {
+                let applied = transaction
+                    .validate()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| Ok(ledger.apply(i + 1, transaction)?));
+                applied.with_context(|| format!("invalid transaction {i}"))
+            })?;
+        let now = Utc::now()
+            .timestamp_nanos_opt()
+            .context("clock is outside the representable range")?;
+        Ok(Block {
+            version: BLOCK_VERSION,
+            prev: self.hashes.last().copied(),
+            // Timestamps may not go backwards even if the clock does.
+            timestamp: self.tip().map_or(now, |parent| now.max(parent.timestamp)),
+            transactions: iter::once(coinbase).chain(transactions).collect(),
+            bits: self.expected_bits(height),
+            nonce: 0,
+        })
+    }
+
+    fn push(&mut self, block: Block) {
+        let hash = self.hash_block(&block);
+        let work = self.chain_work().saturating_add(block.work());
+        self.heights.insert(hash, self.blocks.len());
+        self.hashes.push(hash);
+        self.work.push(work);
+        self.ledger.connect(&block);
+        self.blocks.push(block);
+    }
+
+    pub fn hash_block(&self, block: &Block) -> BlockHash {
+        Midstate::new(block).digest(block.nonce)
+    }
+
+    /// The compact target the block at `height` must be mined against, given
+    /// the blocks below it.
+    ///
+    /// Panics if `height` is beyond the next block's, as the blocks below it
+    /// aren't known.
+    pub fn expected_bits(&self, height: usize) -> u32 {
+        assert!(
+            height <= self.blocks.len(),
+            "height {height} is beyond the next block"
+        );
+        self.bits_after(self.parent_hash(height), height)
+    }
+
+    /// Like [`Blockchain::expected_bits`], for a block at `height` on the
+    /// branch through `parent`.
+    fn bits_after(&self, parent: Option<BlockHash>, height: usize) -> u32 {
+        self.bits_along(height, |height| {
+            self.ancestor(parent, height)
+                .map(|block| (block.timestamp, block.bits))
+        })
+        .expect("ancestor of a known block")
+    }
+
+    /// The compact target of a block at `height` whose ancestors' timestamps
+    /// and bits `ancestor` looks up by height, e.g. along headers being
+    /// synced. Fails if it lacks one the target depends on.
+    pub(crate) fn bits_along(
+        &self,
+        height: usize,
+        ancestor: impl Fn(usize) -> Option<(i64, u32)>,
+    ) -> Option<u32> {
+        let Some(retarget) = self.retarget.filter(|_| height > 0) else {
+            return Some(self.target.to_compact());
+        };
+        let (last_timestamp, prev_bits) = ancestor(height - 1)?;
+        if !height.is_multiple_of(retarget.interval) {
+            return Some(prev_bits);
+        }
+        let (first_timestamp, _) = ancestor(height - retarget.interval)?;
+        let expected = retarget
+            .spacing
+            .as_millis()
+            .saturating_mul(retarget.interval as u128 - 1);
+        let actual = last_timestamp.saturating_sub(first_timestamp).max(0) as u128 / 1_000_000;
+        let actual = actual.clamp(
+            expected / retarget.max_adjustment as u128,
+            expected.saturating_mul(retarget.max_adjustment as u128),
+        );
+        // Scaling takes a 64-bit ratio, so drop precision the larger side
+        // doesn't leave room for.
+        let shift = (u128::BITS - expected.max(actual).leading_zeros()).saturating_sub(u64::BITS);
+        // An undecodable parent target is a fault of its own, reported there.
+        let target = Target::from_compact(prev_bits)
+            .unwrap_or(self.target)
+            .scale(
+                (actual >> shift) as u64,
+                ((expected >> shift) as u64).max(1),
+            )
+            .min(self.target);
+        Some(target.to_compact())
+    }
+
+    /// Checks what a header alone shows about the block at `height`: that
+    /// it's mined against the `expected` bits, which the chain can't work
+    /// out for headers past its tip, and that its hash meets them.
+    pub fn check_header(
+        &self,
+        height: usize,
+        header: &Header,
+        expected: u32,
+    ) -> Result<(), ValidationError> {
+        let hash = header.hash();
+        if header.bits != expected {
+            return Err(ValidationError::BadTarget {
+                height,
+                hash,
+                expected,
+                actual: header.bits,
+            });
+        }
+        Self::check_work(height, &hash, header.bits, expected)
+    }
+
+    fn check_work(
+        height: usize,
+        hash: &BlockHash,
+        bits: u32,
+        expected: u32,
+    ) -> Result<(), ValidationError> {
+        let bad_target = || ValidationError::BadTarget {
+            height,
+            hash: *hash,
+            expected,
+            actual: bits,
+        };
+        let target = Target::from_compact(bits).map_err(|_| bad_target())?;
+        if !target.is_met_by(hash) {
+            return Err(ValidationError::InsufficientWork {
+                height,
+                hash: *hash,
+                target,
+            });
+        }
+        Ok(())
+    }
+
+    /// Stops at the first fault; see [`Blockchain::verify_report`] for all of
+    /// them.
+    pub fn verify_chain(&self) -> Result<(), ValidationError> {
+        let mut faults = Vec::new();
+        let mut ledger = Ledger::new(self.ledger.model);
+        self.blocks
+            .iter()
+            .enumerate()
+            .try_for_each(|(height, block)| {
+                let parent = self.parent_hash(height);
+                self.block_faults(height, block, parent, &mut ledger, &mut faults);
+                faults.drain(..).next().map_or(Ok(()), Err)
+            })
+    }
+
+    /// Checks every block, collecting all faults instead of stopping at the
+    /// first one.
+    pub fn verify_report(&self) -> ValidationReport {
+        let mut faults = Vec::new();
+        let mut ledger = Ledger::new(self.ledger.model);
+        self.blocks.iter().enumerate().for_each(|(height, block)| {
+            let parent = self.parent_hash(height);
+            self.block_faults(height, block, parent, &mut ledger, &mut faults)
+        });
+        let valid_prefix = faults
+            .iter()
+            .map(ValidationError::height)
+            .min()
+            .unwrap_or(self.blocks.len());
+        ValidationReport {
+            len: self.blocks.len(),
+            valid_prefix,
+            valid_tip: valid_prefix
+                .checked_sub(1)
+                .map(|height| self.hashes[height]),
+            faults,
+        }
+    }
+
+    fn parent_hash(&self, height: usize) -> Option<BlockHash> {
+        height.checked_sub(1).map(|parent| self.hashes[parent])
+    }
+
+    /// Pushes every fault found in the block at `height` on the branch
+    /// through `parent`: its target and proof-of-work, its coinbase and
+    /// other transactions, and either the genesis rules or its link to the
+    /// parent. `ledger` is as of the parent, and has the block's transfers
+    /// applied to it.
+    fn block_faults(
+        &self,
+        height: usize,
+        block: &Block,
+        parent: Option<BlockHash>,
+        ledger: &mut Ledger,
+        faults: &mut Vec<ValidationError>,
+    ) {
+        let hash = self.hash_block(block);
+        if block.version != BLOCK_VERSION {
+            faults.push(ValidationError::BadVersion {
+                height,
+                hash,
+                version: block.version,
+            });
+        }
+        let expected_bits = self.bits_after(parent, height);
+        if block.bits != expected_bits {
+            faults.push(ValidationError::BadTarget {
+                height,
+                hash,
+                expected: expected_bits,
+                actual: block.bits,
+            });
+        } else {
+            faults.extend(Self::check_work(height, &hash, block.bits, expected_bits).err());
+        }
+        if let Err(error) = self.check_coinbase(height, block) {
+            faults.push(ValidationError::BadCoinbase {
+                height,
+                hash,
+                error,
+            });
+        }
+        ledger.open_block();
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            let validated = match transaction.is_coinbase() {
+                true => Ok(()),
+                false => transaction.validate(),
+            };
+            let fault = match validated {
+                Err(error) if error.is_signature_error() => ValidationError::BadSignature {
+                    height,
+                    hash,
+                    index,
+                    error,
+                },
+                Err(error) => ValidationError::BadTransaction {
+                    height,
+                    hash,
+                    index,
+                    error,
+                },
+                Ok(()) => match ledger.apply(index, transaction) {
+                    Err(LedgerError::Account(error)) => ValidationError::BadTransfer {
+                        height,
+                        hash,
+                        index,
+                        error,
+                    },
+                    Err(LedgerError::Utxo(error)) => ValidationError::BadSpend {
+                        height,
+                        hash,
+                        error,
+                    },
+                    Ok(()) => continue,
+                },
+            };
+            faults.push(fault);
+        }
+        let Some(expected) = parent else {
+            if let Some(prev) = &block.prev {
+                faults.push(ValidationError::BadGenesis { hash, prev: *prev });
+            }
+            return;
+        };
+        let parent = self
+            .ancestor(Some(expected), height - 1)
+            .expect("parent of a known block");
+        if block.timestamp < parent.timestamp {
+            faults.push(ValidationError::BadTimestamp {
+                height,
+                hash,
+                timestamp: block.timestamp(),
+                parent: parent.timestamp(),
+            });
+        }
+        if block.prev != Some(expected) {
+            faults.push(ValidationError::HashMismatch {
+                height,
+                hash,
+                expected,
+                actual: block.prev,
+            });
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Cumulative work of the canonical chain.
+    pub fn chain_work(&self) -> u128 {
+        self.work.last().copied().unwrap_or(0)
+    }
+
+    pub fn height_of(&self, hash: &BlockHash) -> Option<usize> {
+        self.heights.get(hash).copied()
+    }
+
+    pub fn hash_at(&self, height: usize) -> Option<BlockHash> {
+        self.hashes.get(height).copied()
+    }
+
+    pub fn tip(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    /// Iterates from genesis to the tip; call `.rev()` to walk backwards.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Block> + ExactSizeIterator {
+        self.blocks.iter()
+    }
+}
+
+impl fmt::Debug for Blockchain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut blocks = self.iter().rev();
+        blocks
+            .next()
+            .into_iter()
+            .try_for_each(|block| write!(f, "{block:?}"))?;
+        blocks.try_for_each(|block| write!(f, "\n^\n{block:?}"))
+    }
+}
+
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct(core::any::type_name::<Self>())
+            .field("prev", &format_args!("{:?}", self.prev))
+            .field("timestamp", &format_args!("{:?}", self.timestamp()))
+            .field(
+                "transactions",
+                &format_args!(
+                    "[{}]",
+                    self.transactions
+                        .iter()
+                        .map(Transaction::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )
+            .field("bits", &format_args!("{:#010x}", self.bits))
+            .finish()
+    }
+}
@@ -1,135 +1,396 @@
-use core::fmt;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
+};
 
 use anyhow::Context;
-use base64::prelude::*;
-use chrono::{DateTime, Utc};
-use sha2::{Digest, Sha256};
+use clap::{Parser, Subcommand, ValueEnum};
+use pz1::{
+    codec::DecodeError,
+    generate_key,
+    hash::{self, Hex},
+    pow::{Retarget, RetargetError},
+    Address, BlockHash, Blockchain, ImportError, Node, OutPoint, SigningKey, StateModel, Subsidy,
+    Transaction, ValidationError,
+};
 
-fn main() -> anyhow::Result<()> {
-    let mut blockchain = Blockchain::new(3);
-    blockchain.add("asdfadfas".into());
-    blockchain.add("foo".into());
-    blockchain.add("bar".into());
-    blockchain.add("baz".into());
-    blockchain.add("egg".into());
-    blockchain.add("balls".into());
-    // blockchain.tail.as_mut().unwrap().prev.as_mut().unwrap().1 = "invalid hash".to_owned();
-    blockchain.verify_chain()?;
-    println!("{blockchain:?}");
-    Ok(())
+/// Exit code for a chain that fails validation, can't be decoded or
+/// imported or has invalid rules, as opposed to 1 for any other error.
+const EXIT_INVALID: u8 = 2;
+
+/// How long `node` waits before reconnecting to a peer that isn't up yet.
+const PEER_RETRY: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+#[command(about = "Proof-of-work blockchain kept in a chain file")]
+struct Cli {
+    /// Chain file to operate on.
+    #[arg(long, global = true, default_value = "blockchain.bin")]
+    chain: PathBuf,
+    /// Worker threads used for mining [default: available parallelism].
+    #[arg(long, global = true)]
+    threads: Option<NonZeroUsize>,
+    /// Address to pay the reward of mined blocks to; without it they claim
+    /// nothing.
+    #[arg(long, global = true)]
+    miner: Option<Address>,
+    #[command(subcommand)]
+    command: Command,
 }
 
-pub struct Block {
-    prev: Option<(Box<Self>, String)>,
-    timestamp: DateTime<Utc>,
-    transaction: String,
-    nonce: u64,
+#[derive(Subcommand)]
+enum Command {
+    /// Create an empty chain file.
+    Init {
+        /// Leading zero bits block hashes must have, below 256.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(..256))]
+        difficulty: u32,
+        /// Retarget the difficulty every this many blocks.
+        #[arg(long, requires = "block_time")]
+        retarget_interval: Option<usize>,
+        /// Target seconds between blocks when retargeting.
+        #[arg(long, requires = "retarget_interval")]
+        block_time: Option<u64>,
+        /// Largest factor the difficulty changes by per retarget.
+        #[arg(long, default_value_t = 4)]
+        max_adjustment: u32,
+        /// Coins the first blocks may mint [default: 5000000000].
+        #[arg(long)]
+        subsidy: Option<u64>,
+        /// Halve the subsidy every this many blocks [default: 210000].
+        #[arg(long)]
+        halving_interval: Option<usize>,
+        /// Coins that may ever be minted [default: 2100000000000000].
+        #[arg(long)]
+        max_supply: Option<u64>,
+        /// Whether transfers spend unspent outputs (`utxo`) or debit account
+        /// balances (`accounts`).
+        #[arg(long, default_value_t = StateModel::Utxo)]
+        state: StateModel,
+    },
+    /// Generate a key pair, printing the secret key and the address.
+    Keygen,
+    /// Print a signed transaction as JSON, for use with `add`.
+    Sign {
+        /// Sender's secret key, in hex.
+        #[arg(long)]
+        key: String,
+        #[arg(long)]
+        to: Address,
+        #[arg(long)]
+        amount: u64,
+        #[arg(long, default_value_t = 0)]
+        fee: u64,
+        #[arg(long, default_value_t = 0)]
+        nonce: u64,
+        /// Unspent output to pay from, as `<tx id>:<index>` as `unspent`
+        /// lists them; may be repeated. Chains following accounts need none.
+        #[arg(long = "input")]
+        inputs: Vec<OutPoint>,
+        #[arg(long, default_value = "")]
+        memo: String,
+    },
+    /// Mine a block with the given JSON transactions and append it. With
+    /// none, the block only pays `--miner` its reward.
+    Add { transactions: Vec<String> },
+    /// Check every block, printing all faults found.
+    Verify,
+    /// Print the whole chain, or a single block.
+    Show {
+        #[arg(long, conflicts_with = "hash")]
+        height: Option<usize>,
+        /// Block hash, in hex or base64.
+        #[arg(long)]
+        hash: Option<BlockHash>,
+    },
+    /// Print an address's balance and nonce.
+    Balance {
+        address: Address,
+        /// As of the block at this height instead of the tip.
+        #[arg(long)]
+        height: Option<usize>,
+    },
+    /// List the unspent outputs, or only those an address owns.
+    Unspent {
+        #[arg(long)]
+        owner: Option<Address>,
+    },
+    /// Write the chain to stdout or a file.
+    Export {
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Create the chain file from an export, which must be valid.
+    Import {
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        input: PathBuf,
+    },
+    /// Share the chain with peers over TCP until interrupted, taking signed
+    /// JSON transactions to mine, one per line, on stdin.
+    Node {
+        /// Address to accept peers on.
+        #[arg(long, default_value = "127.0.0.1:7000")]
+        listen: SocketAddr,
+        /// Peer to connect to, retried until it's up; may be repeated.
+        #[arg(long = "peer")]
+        peers: Vec<SocketAddr>,
+        /// Mine a block of the pending transactions every this many seconds.
+        #[arg(long)]
+        mine_every: Option<u64>,
+    },
 }
 
-struct Blockchain {
-    tail: Option<Box<Block>>,
-    difficulty: usize,
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Json,
+    Bin,
 }
 
-impl Blockchain {
-    fn new(difficulty: usize) -> Self {
-        Self {
-            tail: None,
-            difficulty,
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            let invalid = error.chain().any(|cause| {
+                cause.is::<ValidationError>()
+                    || cause.is::<DecodeError>()
+                    || cause.is::<ImportError>()
+                    || cause.is::<RetargetError>()
+            });
+            match invalid {
+                true => ExitCode::from(EXIT_INVALID),
+                false => ExitCode::FAILURE,
+            }
         }
     }
+}
 
-    fn add(&mut self, transaction: String) {
-        let prev = self.tail.take().map(|block| {
-            let hash = self.hash_block(&block);
-            (block, hash)
-        });
-        let new_block = Block {
-            prev,
-            timestamp: Utc::now(),
-            transaction,
-            nonce: 0,
-        };
-        self.tail = Some(Box::new(self.mine_block(new_block)));
-    }
-
-    fn mine_block(&self, mut block: Block) -> Block {
-        block.nonce = Default::default();
-        while self.check_hash(&self.hash_block(&block)).is_err() {
-            block.nonce += 1;
+fn run(cli: Cli) -> anyhow::Result<ExitCode> {
+    let threads = match cli.threads {
+        Some(threads) => threads,
+        None => std::thread::available_parallelism()?,
+    };
+    let existing = || {
+        anyhow::ensure!(
+            cli.chain.exists(),
+            "no chain file at {:?}, create one with `init` or `import`",
+            cli.chain
+        );
+        Ok(&cli.chain)
+    };
+    let open = || {
+        let path = existing()?;
+        let blockchain = Blockchain::new(0).with_mining_threads(threads);
+        match cli.miner {
+            Some(miner) => blockchain.with_miner(miner),
+            None => blockchain,
         }
-        block
-    }
-
-    fn hash_block(&self, block: &Block) -> String {
-        let timestamp = block.timestamp.to_string();
-        let nonce = block.nonce.to_le_bytes();
-        let mut hasher = Sha256::new();
-        block.prev.as_ref().inspect(|block| hasher.update(&block.1));
-        let digest = hasher
-            .chain_update(timestamp)
-            .chain_update(&block.transaction)
-            .chain_update(nonce)
-            .finalize();
-        BASE64_STANDARD.encode(digest)
-    }
-
-    fn check_hash(&self, hash: &str) -> anyhow::Result<()> {
-        if !hash.chars().take(self.difficulty).all(|c| c == '0') {
-            anyhow::bail!("invalid hash: {hash:?}")
+        .open(path)
+    };
+    match cli.command {
+        Command::Init {
+            difficulty,
+            retarget_interval,
+            block_time,
+            max_adjustment,
+            subsidy,
+            halving_interval,
+            max_supply,
+            state,
+        } => {
+            let default = Subsidy::default();
+            let subsidy = Subsidy {
+                initial: subsidy.unwrap_or(default.initial),
+                halving_interval: halving_interval.unwrap_or(default.halving_interval),
+                max_supply: max_supply.unwrap_or(default.max_supply),
+            };
+            anyhow::ensure!(
+                subsidy.halving_interval >= 1,
+                "halving interval must be at least 1"
+            );
+            let mut blockchain = Blockchain::new(difficulty)
+                .with_subsidy(subsidy)
+                .with_state_model(state);
+            if let (Some(interval), Some(block_time)) = (retarget_interval, block_time) {
+                let retarget = Retarget {
+                    interval,
+                    spacing: Duration::from_secs(block_time),
+                    max_adjustment,
+                };
+                retarget.validate()?;
+                blockchain = blockchain.with_retarget(retarget);
+            }
+            blockchain.save(&cli.chain)?;
         }
-        Ok(())
-    }
-
-    fn verify_chain(&self) -> anyhow::Result<()> {
-        let mut blocks = self.iter();
-        let tail = blocks.next();
-        blocks
-            .try_fold(tail, |next, prev| {
-                let expected_hash = next
-                    .and_then(|b| b.prev.as_ref().map(|(_, h)| h.as_str()))
-                    .unwrap();
-                match self.hash_block(prev) {
-                    h if h != expected_hash => Err(anyhow::anyhow!(
-                        "hash mismatch: specified {expected_hash:?} when expected {h:?}"
-                    )),
-                    h => self.check_hash(&h).map(|()| Some(prev)),
+        Command::Keygen => {
+            let key = generate_key();
+            println!("secret key: {}", Hex(&key.to_bytes()));
+            println!("address:    {}", Address::of(&key));
+        }
+        Command::Sign {
+            key,
+            to,
+            amount,
+            fee,
+            nonce,
+            inputs,
+            memo,
+        } => {
+            let key = SigningKey::from_bytes(&hash::parse_hex(&key).context("invalid secret key")?);
+            let transaction = Transaction {
+                sender: Address::of(&key),
+                recipient: to,
+                amount,
+                fee,
+                nonce,
+                inputs,
+                memo,
+                signature: None,
+            }
+            .sign(&key);
+            println!("{}", serde_json::to_string(&transaction)?);
+        }
+        Command::Add { transactions } => {
+            let transactions = transactions
+                .iter()
+                .map(|json| serde_json::from_str(json).context("invalid transaction JSON"))
+                .collect::<anyhow::Result<_>>()?;
+            let mut blockchain = open()?;
+            blockchain.add(transactions)?;
+            let height = blockchain.len() - 1;
+            println!("{height} {}", blockchain.hash_at(height).unwrap());
+        }
+        Command::Verify => {
+            let report = Blockchain::new(0)
+                .open_unverified(existing()?)?
+                .verify_report();
+            println!("{report}");
+            if !report.is_valid() {
+                return Ok(ExitCode::from(EXIT_INVALID));
+            }
+        }
+        Command::Show { height, hash } => {
+            let blockchain = open()?;
+            let found = match (height, hash) {
+                (Some(height), _) => {
+                    Some(blockchain.get(height).context("no block at this height")?)
                 }
-                .with_context(|| format!("invalid block in chain: {next:?}"))
-            })
-            .map(|_| ())
-    }
-
-    fn iter(&self) -> impl Iterator<Item = &Block> {
-        core::iter::successors(self.tail.as_ref(), |next| {
-            next.prev.as_ref().map(|(prev, _)| prev)
-        })
-        .map(Box::as_ref)
-    }
-}
-
-impl fmt::Debug for Blockchain {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut blocks = core::iter::successors(self.tail.as_ref(), |next| {
-            next.prev.as_ref().map(|(prev, _)| prev)
-        });
-        blocks
-            .next()
-            .into_iter()
-            .try_for_each(|block| write!(f, "{block:?}"))?;
-        blocks.try_for_each(|block| write!(f, "\n^\n{block:?}"))
-    }
-}
-
-impl fmt::Debug for Block {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct(core::any::type_name::<Self>())
-            .field(
-                "prev",
-                &format_args!("{:?}", self.prev.as_ref().map(|(_, hash)| hash)),
-            )
-            .field("timestamp", &format_args!("{:?}", self.timestamp))
-            .field("transaction", &format_args!("{}", self.transaction))
-            .finish()
+                (_, Some(hash)) => Some(
+                    blockchain
+                        .get_by_hash(&hash)
+                        .context("no block with this hash")?,
+                ),
+                (None, None) => None,
+            };
+            match found {
+                Some(found) => {
+                    println!("height: {}", found.height);
+                    println!("hash:   {}", found.hash);
+                    println!("{:?}", found.block);
+                }
+                None => println!("{blockchain:?}"),
+            }
+        }
+        Command::Balance { address, height } => {
+            let blockchain = open()?;
+            let account = match height {
+                Some(height) => blockchain
+                    .accounts_at(height)
+                    .context("no block at this height")?
+                    .get(&address),
+                None => blockchain.accounts().get(&address),
+            };
+            println!("balance: {}", account.balance);
+            println!("nonce:   {}", account.nonce);
+        }
+        Command::Unspent { owner } => {
+            let blockchain = open()?;
+            let mut unspent: Vec<_> = blockchain
+                .utxos()
+                .iter()
+                .filter(|(_, output)| owner.is_none_or(|owner| output.owner == owner))
+                .collect();
+            unspent.sort_by_key(|(outpoint, _)| **outpoint);
+            for (outpoint, output) in unspent {
+                println!("{outpoint} {} {}", output.owner, output.amount);
+            }
+        }
+        Command::Export { format, output } => {
+            let blockchain = open()?;
+            let bytes = match format {
+                Format::Json => blockchain.to_json()?.into_bytes(),
+                Format::Bin => blockchain.to_bytes(),
+            };
+            match output {
+                Some(path) => {
+                    fs::write(&path, bytes).with_context(|| format!("failed to write {path:?}"))?
+                }
+                None => io::stdout().write_all(&bytes)?,
+            }
+        }
+        Command::Import { format, input } => {
+            let mut bytes = Vec::new();
+            fs::File::open(&input)
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .with_context(|| format!("failed to read {input:?}"))?;
+            let mut blockchain = match format {
+                Format::Json => Blockchain::from_json(
+                    core::str::from_utf8(&bytes).context("JSON export is not UTF-8")?,
+                )?,
+                Format::Bin => Blockchain::from_bytes(&bytes)?,
+            };
+            blockchain.save(&cli.chain)?;
+            println!("imported {} blocks", blockchain.len());
+        }
+        Command::Node {
+            listen,
+            peers,
+            mine_every,
+        } => {
+            let node = Node::new(open()?);
+            let events = node.events();
+            std::thread::spawn(move || events.iter().for_each(|event| eprintln!("{event}")));
+            let local = node.listen(listen)?;
+            eprintln!("listening on {local}");
+            for peer in peers {
+                let node = node.clone();
+                std::thread::spawn(move || {
+                    while let Err(error) = node.connect(peer) {
+                        eprintln!("{peer}: {error:#}, retrying");
+                        std::thread::sleep(PEER_RETRY);
+                    }
+                });
+            }
+            let submitter = node.clone();
+            std::thread::spawn(move || {
+                for line in io::stdin().lines().map_while(Result::ok) {
+                    let submitted = serde_json::from_str(&line)
+                        .context("invalid transaction JSON")
+                        .and_then(|transaction| Ok(submitter.submit(transaction)?));
+                    if let Err(error) = submitted {
+                        eprintln!("rejected transaction: {error:#}");
+                    }
+                }
+            });
+            loop {
+                match mine_every {
+                    Some(seconds) => {
+                        std::thread::sleep(Duration::from_secs(seconds));
+                        let hash = node.mine()?;
+                        eprintln!("mined height {}: {hash}", node.blockchain().len() - 1);
+                    }
+                    None => std::thread::park(),
+                }
+            }
+        }
     }
+    Ok(ExitCode::SUCCESS)
 }
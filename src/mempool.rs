@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use crate::{hash::Hex, Accounts, Blockchain, Reorg, Transaction, TransactionError};
+
+/// Default limit on the bytes a mined block's transactions take up.
+pub const MAX_BLOCK_SIZE: usize = 1 << 20;
+
+/// Why [`Mempool::insert`] turned a transaction away.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MempoolError {
+    #[error("transaction {} is already pending", Hex(.0))]
+    Duplicate([u8; 32]),
+    #[error(transparent)]
+    Invalid(#[from] TransactionError),
+}
+
+/// Valid transactions waiting to be mined, by id.
+#[derive(Default)]
+pub struct Mempool {
+    pending: HashMap<[u8; 32], Entry>,
+}
+
+struct Entry {
+    transaction: Transaction,
+    /// Bytes the transaction takes up in a block: its encoding and the
+    /// `u32` length before it.
+    size: usize,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn contains(&self, id: &[u8; 32]) -> bool {
+        self.pending.contains_key(id)
+    }
+
+    /// Adds a transaction once it passes [`Transaction::validate`].
+    pub fn insert(&mut self, transaction: Transaction) -> Result<(), MempoolError> {
+        let id = transaction.id();
+        if self.contains(&id) {
+            return Err(MempoolError::Duplicate(id));
+        }
+        transaction.validate()?;
+        let size = 4 + transaction.to_bytes().len();
+        self.pending.insert(id, Entry { transaction, size });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &[u8; 32]) -> Option<Transaction> {
+        self.pending.remove(id).map(|entry| entry.transaction)
+    }
+
+    /// The transactions for the next block on the canonical tip of
+    /// `blockchain`, best fee rate first, skipping any that would take it
+    /// past `max_size` bytes. Those spending outputs that aren't there are
+    /// left out, and those whose nonce is ahead wait for the transactions
+    /// before them to be included.
+    pub fn assemble(&self, max_size: usize, blockchain: &Blockchain) -> Vec<Transaction> {
+        let mut entries: Vec<_> = self.pending.iter().collect();
+        entries.sort_by(|(a_id, a), (b_id, b)| {
+            // Compares the fees per byte without dividing.
+            let a_rate = a.transaction.fee as u128 * b.size as u128;
+            let b_rate = b.transaction.fee as u128 * a.size as u128;
+            b_rate.cmp(&a_rate).then(a_id.cmp(b_id))
+        });
+        let mut ledger = blockchain.ledger.rewound(0);
+        ledger.open_block();
+        let mut size = 0;
+        let mut transactions = Vec::new();
+        loop {
+            let included = transactions.len();
+            entries.retain(|(_, entry)| {
+                if size + entry.size > max_size {
+                    return false;
+                }
+                // The coinbase comes first.
+                let index = transactions.len() + 1;
+                if ledger.apply(index, &entry.transaction).is_err() {
+                    return true;
+                }
+                size += entry.size;
+                transactions.push(entry.transaction.clone());
+                false
+            });
+            if transactions.len() == included {
+                return transactions;
+            }
+        }
+    }
+
+    /// Follows the chain through a reorganization to a tip with `accounts`:
+    /// transactions from disconnected blocks are pending again, unless a
+    /// connected block includes them too, and then [`Mempool::prune`]s.
+    pub fn update(&mut self, reorg: &Reorg, accounts: &Accounts) {
+        reorg
+            .disconnected
+            .iter()
+            .flat_map(|block| block.transactions.iter().cloned())
+            .for_each(|transaction| {
+                let _ = self.insert(transaction);
+            });
+        reorg
+            .connected
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .for_each(|transaction| {
+                self.remove(&transaction.id());
+            });
+        self.prune(accounts);
+    }
+
+    /// Drops transactions that can never be mined on top of `accounts`, as
+    /// their sender has already used their nonce.
+    pub fn prune(&mut self, accounts: &Accounts) {
+        self.pending.retain(|_, entry| {
+            entry.transaction.nonce >= accounts.get(&entry.transaction.sender).nonce
+        });
+    }
+}
+
+impl Blockchain {
+    /// Mines a block of the best paying transactions in `mempool`, up to
+    /// `max_size` bytes of them, and evicts them from it.
+    pub fn add_from(&mut self, mempool: &mut Mempool, max_size: usize) -> anyhow::Result<()> {
+        self.add(mempool.assemble(max_size, self))?;
+        let tip = self.tip().expect("block just added");
+        tip.transactions.iter().for_each(|transaction| {
+            mempool.remove(&transaction.id());
+        });
+        mempool.prune(self.accounts());
+        Ok(())
+    }
+}
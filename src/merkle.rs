@@ -0,0 +1,176 @@
+use sha2::{Digest, Sha256};
+
+use crate::Transaction;
+
+/// Leaves and inner nodes are hashed with different prefixes, so a proof for
+/// an inner node can never pass as one for a transaction.
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+
+pub fn leaf_hash(transaction: &Transaction) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([LEAF])
+        .chain_update(transaction.to_bytes())
+        .finalize()
+        .into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([NODE])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// Root of the tree over `transactions`, all zeroes when there are none. A
+/// node without a sibling is carried up to the next level unchanged rather
+/// than paired with itself.
+pub fn root(transactions: &[Transaction]) -> [u8; 32] {
+    let mut level: Vec<_> = transactions.iter().map(leaf_hash).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.first().copied().unwrap_or_default()
+}
+
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [node] => *node,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Proof that the transaction at `index` among `leaf_count` is committed to by
+/// a Merkle root, listing the siblings on its path from the leaf upwards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub leaf_count: usize,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+    pub fn new(transactions: &[Transaction], index: usize) -> Option<Self> {
+        if index >= transactions.len() {
+            return None;
+        }
+        let mut level: Vec<_> = transactions.iter().map(leaf_hash).collect();
+        let mut siblings = Vec::new();
+        let mut position = index;
+        while level.len() > 1 {
+            siblings.extend(level.get(position ^ 1));
+            level = next_level(&level);
+            position /= 2;
+        }
+        Some(Self {
+            index,
+            leaf_count: transactions.len(),
+            siblings,
+        })
+    }
+
+    pub fn verify(&self, root: &[u8; 32], transaction: &Transaction) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let (mut hash, mut position, mut width) =
+            (leaf_hash(transaction), self.index, self.leaf_count);
+        while width > 1 {
+            if position ^ 1 < width {
+                let Some(sibling) = siblings.next() else {
+                    return false;
+                };
+                hash = match position % 2 {
+                    0 => node_hash(&hash, sibling),
+                    _ => node_hash(sibling, &hash),
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        siblings.next().is_none() && &hash == root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Address;
+
+    fn transactions(count: u64) -> Vec<Transaction> {
+        (0..count)
+            .map(|i| Transaction::coinbase(Address::COINBASE, i + 1, i as usize))
+            .collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_for_one_to_seven_leaves() {
+        for count in 1..=7 {
+            let transactions = transactions(count);
+            let root = root(&transactions);
+            for (index, transaction) in transactions.iter().enumerate() {
+                let proof = MerkleProof::new(&transactions, index).unwrap();
+                assert!(proof.verify(&root, transaction), "{index} of {count}");
+                let other = &transactions[(index + 1) % transactions.len()];
+                assert_eq!(proof.verify(&root, other), count == 1);
+            }
+        }
+    }
+
+    #[test]
+    fn single_leaf_is_its_own_root() {
+        let transactions = transactions(1);
+        assert_eq!(root(&transactions), leaf_hash(&transactions[0]));
+        assert!(MerkleProof::new(&transactions, 0)
+            .unwrap()
+            .siblings
+            .is_empty());
+        assert_eq!(root(&[]), [0; 32]);
+    }
+
+    #[test]
+    fn odd_node_is_carried_up() {
+        let transactions = transactions(3);
+        let leaves: Vec<_> = transactions.iter().map(leaf_hash).collect();
+        let expected = node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2]);
+        assert_eq!(root(&transactions), expected);
+        let proof = MerkleProof::new(&transactions, 2).unwrap();
+        assert_eq!(proof.siblings, vec![node_hash(&leaves[0], &leaves[1])]);
+    }
+
+    #[test]
+    fn out_of_range_index_is_rejected() {
+        let transactions = transactions(5);
+        let root = root(&transactions);
+        assert_eq!(MerkleProof::new(&transactions, 5), None);
+        let mut proof = MerkleProof::new(&transactions, 4).unwrap();
+        proof.index = 5;
+        assert!(!proof.verify(&root, &transactions[4]));
+    }
+
+    #[test]
+    fn extra_or_missing_siblings_are_rejected() {
+        let transactions = transactions(6);
+        let root = root(&transactions);
+        let proof = MerkleProof::new(&transactions, 3).unwrap();
+
+        let mut extra = proof.clone();
+        extra.siblings.push([0; 32]);
+        assert!(!extra.verify(&root, &transactions[3]));
+
+        let mut missing = proof.clone();
+        missing.siblings.pop();
+        assert!(!missing.verify(&root, &transactions[3]));
+
+        let mut wrong_count = proof;
+        wrong_count.leaf_count = 4;
+        assert!(!wrong_count.verify(&root, &transactions[3]));
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{self, Read};
+
+use crate::{
+    codec::{self, DecodeError, Reader},
+    Block, BlockHash, Header,
+};
+
+/// Largest message payload a peer may send.
+pub const MAX_MESSAGE_LEN: usize = 8 << 20;
+
+/// Most hashes announced in a single [`Message::Inv`].
+pub const MAX_INV: usize = 500;
+
+/// Most headers sent in reply to a single [`Message::GetHeaders`].
+pub const MAX_HEADERS: usize = 2000;
+
+/// What peers send each other, each framed as a [`codec`] record whose
+/// payload is a `u8` tag followed by the fields below, integers
+/// little-endian and hash lists as a `u32` count followed by the hashes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Opens the handshake: the `u32` protocol version, the genesis hash as
+    /// a `u8` flag followed by the hash when set, and the `u64` chain length.
+    Version {
+        protocol: u32,
+        genesis: Option<BlockHash>,
+        height: u64,
+    },
+    /// Completes the handshake.
+    Verack,
+    /// Announces blocks the sender has.
+    Inv(Vec<BlockHash>),
+    /// Asks for the `Headers` of the canonical blocks following the first
+    /// hash in this locator that the receiver knows, or from genesis if none.
+    GetHeaders(Vec<BlockHash>),
+    /// Asks for the blocks with these hashes.
+    GetData(Vec<BlockHash>),
+    /// A block record as in [`Block::encode`].
+    Block(Block),
+    /// Consecutive headers, lowest first, each as the `u32` version, the
+    /// prev hash as in `Version`'s genesis, the `i64` timestamp, the merkle
+    /// root, the `u32` bits and the `u64` nonce.
+    Headers(Vec<Header>),
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Self::Version {
+                protocol,
+                genesis,
+                height,
+            } => {
+                payload.push(0);
+                payload.extend(protocol.to_le_bytes());
+                encode_optional_hash(&mut payload, genesis);
+                payload.extend(height.to_le_bytes());
+            }
+            Self::Verack => payload.push(1),
+            Self::Inv(hashes) => encode_hashes(&mut payload, 2, hashes),
+            Self::GetHeaders(locator) => encode_hashes(&mut payload, 3, locator),
+            Self::GetData(hashes) => encode_hashes(&mut payload, 4, hashes),
+            Self::Block(block) => {
+                payload.push(5);
+                payload.extend(block.encode());
+            }
+            Self::Headers(headers) => {
+                payload.push(6);
+                payload.extend((headers.len() as u32).to_le_bytes());
+                headers
+                    .iter()
+                    .for_each(|header| encode_header(&mut payload, header));
+            }
+        }
+        codec::encode_record(&payload)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader(payload);
+        let message = match reader.u8("message tag")? {
+            0 => Self::Version {
+                protocol: reader.u32("protocol")?,
+                genesis: decode_optional_hash(&mut reader, "genesis")?,
+                height: u64::from_le_bytes(reader.array("height")?),
+            },
+            1 => Self::Verack,
+            2 => Self::Inv(decode_hashes(&mut reader)?),
+            3 => Self::GetHeaders(decode_hashes(&mut reader)?),
+            4 => Self::GetData(decode_hashes(&mut reader)?),
+            5 => {
+                let (block, rest) = Block::decode(reader.0)?;
+                reader.0 = rest;
+                Self::Block(block)
+            }
+            6 => Self::Headers(
+                (0..reader.u32("header count")?)
+                    .map(|_| decode_header(&mut reader))
+                    .collect::<Result<_, _>>()?,
+            ),
+            value => {
+                return Err(DecodeError::InvalidFlag {
+                    field: "message",
+                    value,
+                })
+            }
+        };
+        if !reader.0.is_empty() {
+            return Err(DecodeError::TrailingBytes {
+                field: "message",
+                len: reader.0.len(),
+            });
+        }
+        Ok(message)
+    }
+
+    /// Reads the next message off a peer's stream.
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let payload = codec::read_record(reader, MAX_MESSAGE_LEN)?;
+        Self::decode(&payload).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+fn encode_hashes(payload: &mut Vec<u8>, tag: u8, hashes: &[BlockHash]) {
+    payload.push(tag);
+    payload.extend((hashes.len() as u32).to_le_bytes());
+    hashes.iter().for_each(|hash| payload.extend(hash.0));
+}
+
+fn decode_hashes(reader: &mut Reader) -> Result<Vec<BlockHash>, DecodeError> {
+    (0..reader.u32("hash count")?)
+        .map(|_| reader.array("hash").map(BlockHash))
+        .collect()
+}
+
+fn encode_optional_hash(payload: &mut Vec<u8>, hash: &Option<BlockHash>) {
+    match hash {
+        Some(hash) => {
+            payload.push(1);
+            payload.extend(hash.0);
+        }
+        None => payload.push(0),
+    }
+}
+
+fn decode_optional_hash(
+    reader: &mut Reader,
+    field: &'static str,
+) -> Result<Option<BlockHash>, DecodeError> {
+    match reader.u8(field)? {
+        0 => Ok(None),
+        1 => Ok(Some(BlockHash(reader.array(field)?))),
+        value => Err(DecodeError::InvalidFlag { field, value }),
+    }
+}
+
+fn encode_header(payload: &mut Vec<u8>, header: &Header) {
+    payload.extend(header.version.to_le_bytes());
+    encode_optional_hash(payload, &header.prev);
+    payload.extend(header.timestamp.to_le_bytes());
+    payload.extend(header.merkle_root);
+    payload.extend(header.bits.to_le_bytes());
+    payload.extend(header.nonce.to_le_bytes());
+}
+
+fn decode_header(reader: &mut Reader) -> Result<Header, DecodeError> {
+    Ok(Header {
+        version: reader.u32("version")?,
+        prev: decode_optional_hash(reader, "prev")?,
+        timestamp: i64::from_le_bytes(reader.array("timestamp")?),
+        merkle_root: reader.array("merkle root")?,
+        bits: reader.u32("bits")?,
+        nonce: u64::from_le_bytes(reader.array("nonce")?),
+    })
+}
@@ -0,0 +1,576 @@
+use core::fmt;
+use std::{
+    io::{self, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{
+    mempool::{Mempool, MempoolError, MAX_BLOCK_SIZE},
+    message::{Message, MAX_HEADERS, MAX_INV},
+    sync::HeaderSync,
+    Block, BlockError, BlockHash, Blockchain, Header, Transaction, ValidationError,
+};
+
+/// Version of the peer protocol, which both ends of a connection must speak.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// How long a peer has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the listener checks for new peers.
+const ACCEPT_TICK: Duration = Duration::from_millis(50);
+
+/// How often timed out body requests are handed to other peers.
+const SYNC_TICK: Duration = Duration::from_secs(1);
+
+/// A chain shared with peers over TCP. After a version handshake, each side
+/// asks the other for the headers after its tip, checks their proof-of-work
+/// and downloads the bodies it lacks in parallel from every peer that has
+/// them. From then on, blocks are announced by hash as they're mined or
+/// accepted and fetched by whoever lacks them. Every block received goes
+/// through [`Blockchain::accept`], and a peer sending a bad header or an
+/// invalid block is disconnected.
+#[derive(Clone)]
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    chain: Mutex<Blockchain>,
+    peers: Mutex<Vec<Arc<Peer>>>,
+    sync: Mutex<HeaderSync>,
+    mempool: Mutex<Mempool>,
+    events: Mutex<Option<Sender<Event>>>,
+}
+
+/// Something that happened to a node's peers or chain, see [`Node::events`].
+#[derive(Debug)]
+pub enum Event {
+    /// A handshake succeeded with a peer whose chain has `height` blocks.
+    Connected { addr: SocketAddr, height: u64 },
+    /// A peer was dropped, because of `error` if it didn't just hang up.
+    Disconnected {
+        addr: SocketAddr,
+        error: Option<anyhow::Error>,
+    },
+    /// An inbound connection failed before its handshake completed.
+    Rejected(anyhow::Error),
+    /// A block from `from` made the canonical tip `hash` at `height`.
+    Tip {
+        height: usize,
+        hash: BlockHash,
+        from: SocketAddr,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connected { addr, height } => write!(f, "connected to {addr} at height {height}"),
+            Self::Disconnected { addr, error: None } => write!(f, "{addr} disconnected"),
+            Self::Disconnected {
+                addr,
+                error: Some(error),
+            } => write!(f, "dropping {addr}: {error:#}"),
+            Self::Rejected(error) => write!(f, "inbound peer: {error:#}"),
+            Self::Tip { height, hash, from } => write!(f, "height {height} from {from}: {hash}"),
+        }
+    }
+}
+
+impl Drop for Shared {
+    /// Ends the serve loops still reading from peers.
+    fn drop(&mut self) {
+        let peers = self.peers.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for peer in peers.drain(..) {
+            let writer = peer.writer.lock().unwrap_or_else(PoisonError::into_inner);
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+struct Peer {
+    addr: SocketAddr,
+    writer: Mutex<TcpStream>,
+}
+
+impl Peer {
+    fn send(&self, message: &Message) -> io::Result<()> {
+        self.writer
+            .lock()
+            .expect("peer lock poisoned")
+            .write_all(&message.encode())
+    }
+}
+
+impl Node {
+    pub fn new(blockchain: Blockchain) -> Self {
+        let shared = Arc::new(Shared {
+            chain: Mutex::new(blockchain),
+            peers: Mutex::new(Vec::new()),
+            sync: Mutex::new(HeaderSync::default()),
+            mempool: Mutex::new(Mempool::new()),
+            events: Mutex::new(None),
+        });
+        // Re-requests bodies from stalled peers until the node is dropped.
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || loop {
+            thread::sleep(SYNC_TICK);
+            let Some(shared) = weak.upgrade() else {
+                return;
+            };
+            Self { shared }.request_bodies();
+        });
+        Self { shared }
+    }
+
+    pub fn blockchain(&self) -> MutexGuard<'_, Blockchain> {
+        self.shared.chain.lock().expect("chain lock poisoned")
+    }
+
+    /// Reports what happens from now on to whoever holds the receiver,
+    /// replacing any earlier one. Until then, events are discarded.
+    pub fn events(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        *self.shared.events.lock().expect("events lock poisoned") = Some(sender);
+        receiver
+    }
+
+    /// Addresses of the peers currently connected.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peer_list().iter().map(|peer| peer.addr).collect()
+    }
+
+    /// Accepts peers on `addr` in the background, returning the address
+    /// bound, e.g. to learn the port picked for port 0. The listener closes
+    /// once every handle to the node is dropped.
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        // Polled, so the loop notices the node is gone without a peer
+        // having to connect first.
+        listener.set_nonblocking(true)?;
+        let weak = Arc::downgrade(&self.shared);
+        thread::spawn(move || loop {
+            let accepted = listener.accept();
+            let Some(shared) = weak.upgrade() else {
+                return;
+            };
+            let node = Self { shared };
+            match accepted {
+                Ok((stream, _)) => {
+                    thread::spawn(move || {
+                        let started = stream
+                            .set_nonblocking(false)
+                            .context("failed to accept a peer")
+                            .and_then(|()| node.start(stream));
+                        match started {
+                            Ok((peer, stream)) => node.spawn_serve(peer, stream),
+                            Err(error) => node.emit(Event::Rejected(error)),
+                        }
+                    });
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    drop(node);
+                    thread::sleep(ACCEPT_TICK);
+                }
+                Err(error) => node.emit(Event::Rejected(
+                    anyhow::Error::new(error).context("failed to accept a peer"),
+                )),
+            }
+        });
+        Ok(local)
+    }
+
+    /// Connects to the peer at `addr` and starts syncing with it in the
+    /// background once the handshake succeeds.
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> anyhow::Result<()> {
+        let stream = TcpStream::connect(addr).context("failed to connect")?;
+        let (peer, stream) = self.start(stream)?;
+        self.spawn_serve(peer, stream);
+        Ok(())
+    }
+
+    /// Holds a transaction in the mempool until it's mined.
+    pub fn submit(&self, transaction: Transaction) -> Result<(), MempoolError> {
+        self.mempool().insert(transaction)
+    }
+
+    /// Mines a block of the best paying pending transactions onto the
+    /// canonical tip and announces it to every peer. The chain stays
+    /// unlocked while mining, so peers are served meanwhile; if their blocks
+    /// move the tip, the mined one is accepted as a competing branch.
+    pub fn mine(&self) -> anyhow::Result<BlockHash> {
+        let (template, threads) = {
+            let blockchain = self.blockchain();
+            let transactions = self.mempool().assemble(MAX_BLOCK_SIZE, &blockchain);
+            (
+                blockchain.template(transactions)?,
+                blockchain.mining_threads(),
+            )
+        };
+        let block = template.mine(threads);
+        let hash = block.header().hash();
+        {
+            let mut blockchain = self.blockchain();
+            let reorg = blockchain.accept(block)?;
+            self.mempool().update(&reorg, blockchain.accounts());
+        }
+        self.broadcast(&Message::Inv(vec![hash]), None);
+        Ok(hash)
+    }
+
+    /// Exchanges versions with a new peer, registers it and asks it for the
+    /// blocks we're missing.
+    fn start(&self, mut stream: TcpStream) -> anyhow::Result<(Arc<Peer>, TcpStream)> {
+        let addr = stream.peer_addr()?;
+        let height = self
+            .handshake(&mut stream)
+            .with_context(|| format!("handshake with {addr} failed"))?;
+        self.emit(Event::Connected { addr, height });
+        let peer = Arc::new(Peer {
+            addr,
+            writer: Mutex::new(stream.try_clone()?),
+        });
+        self.shared
+            .peers
+            .lock()
+            .expect("peers lock poisoned")
+            .push(peer.clone());
+        let locator = locator(&self.blockchain());
+        peer.send(&Message::GetHeaders(locator))?;
+        Ok((peer, stream))
+    }
+
+    /// Returns the peer's chain length once both sides have sent a
+    /// compatible version and acknowledged the other's.
+    fn handshake(&self, stream: &mut TcpStream) -> anyhow::Result<u64> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let (genesis, height) = {
+            let blockchain = self.blockchain();
+            (blockchain.hash_at(0), blockchain.len() as u64)
+        };
+        let version = Message::Version {
+            protocol: PROTOCOL_VERSION,
+            genesis,
+            height,
+        };
+        stream.write_all(&version.encode())?;
+        let height = match Message::read(stream)? {
+            Message::Version {
+                protocol,
+                genesis: theirs,
+                height,
+            } => {
+                anyhow::ensure!(
+                    protocol == PROTOCOL_VERSION,
+                    "unsupported protocol version {protocol}"
+                );
+                if let (Some(ours), Some(theirs)) = (genesis, theirs) {
+                    anyhow::ensure!(ours == theirs, "peer is on another chain ({theirs})");
+                }
+                height
+            }
+            message => anyhow::bail!("expected a version, got {message:?}"),
+        };
+        stream.write_all(&Message::Verack.encode())?;
+        match Message::read(stream)? {
+            Message::Verack => {}
+            message => anyhow::bail!("expected a verack, got {message:?}"),
+        }
+        stream.set_read_timeout(None)?;
+        Ok(height)
+    }
+
+    /// Handles the peer's messages on a thread of its own until it
+    /// disconnects or misbehaves. The thread only holds on to the node while
+    /// handling a message, so dropping the node ends it.
+    fn spawn_serve(&self, peer: Arc<Peer>, mut stream: TcpStream) {
+        let weak = Arc::downgrade(&self.shared);
+        thread::spawn(move || loop {
+            let read = Message::read(&mut stream);
+            let Some(shared) = weak.upgrade() else {
+                return;
+            };
+            let node = Self { shared };
+            let result = match read {
+                Ok(message) => match node.handle(&peer, message) {
+                    Ok(()) => continue,
+                    Err(error) => Err(error),
+                },
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+                Err(error) => Err(error.into()),
+            };
+            node.emit(Event::Disconnected {
+                addr: peer.addr,
+                error: result.err(),
+            });
+            node.disconnect(&peer);
+            return;
+        });
+    }
+
+    fn handle(&self, peer: &Peer, message: Message) -> anyhow::Result<()> {
+        match message {
+            Message::Version { .. } | Message::Verack => {
+                anyhow::bail!("unexpected handshake message")
+            }
+            Message::Inv(hashes) => {
+                let missing: Vec<_> = {
+                    let blockchain = self.blockchain();
+                    let sync = self.sync();
+                    hashes
+                        .into_iter()
+                        .filter(|hash| !blockchain.contains(hash) && !sync.is_wanted(hash))
+                        .collect()
+                };
+                if !missing.is_empty() {
+                    peer.send(&Message::GetData(missing))?;
+                }
+            }
+            Message::GetHeaders(locator) => {
+                let headers: Vec<_> = {
+                    let blockchain = self.blockchain();
+                    let start = locator
+                        .iter()
+                        .find_map(|hash| blockchain.height_of(hash))
+                        .map_or(0, |height| height + 1);
+                    blockchain
+                        .iter()
+                        .skip(start)
+                        .take(MAX_HEADERS)
+                        .map(Block::header)
+                        .collect()
+                };
+                if !headers.is_empty() {
+                    peer.send(&Message::Headers(headers))?;
+                }
+            }
+            Message::Headers(headers) => {
+                let next = (headers.len() >= MAX_HEADERS).then(|| headers.last().map(Header::hash));
+                self.add_headers(peer, &headers)?;
+                self.request_bodies();
+                // A full reply may be one page of many.
+                if let Some(last) = next.flatten() {
+                    peer.send(&Message::GetHeaders(vec![last]))?;
+                }
+            }
+            Message::GetData(hashes) => {
+                let blocks: Vec<_> = {
+                    let blockchain = self.blockchain();
+                    hashes
+                        .iter()
+                        .filter_map(|hash| blockchain.get_by_hash(hash))
+                        .map(|found| found.block.clone())
+                        .collect()
+                };
+                blocks
+                    .into_iter()
+                    .try_for_each(|block| peer.send(&Message::Block(block)))?;
+            }
+            Message::Block(block) => {
+                self.receive(peer, block)?;
+                self.request_bodies();
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks each header's link to a known block and its proof-of-work,
+    /// queueing the bodies of those the chain lacks.
+    fn add_headers(&self, peer: &Peer, headers: &[Header]) -> anyhow::Result<()> {
+        let blockchain = self.blockchain();
+        let mut sync = self.sync();
+        headers.iter().try_for_each(|header| {
+            let hash = header.hash();
+            let height = match header.prev {
+                None => 0,
+                Some(prev) => {
+                    blockchain
+                        .locate(&prev)
+                        .map(|(height, _)| height)
+                        .or_else(|| sync.height(&prev))
+                        .with_context(|| format!("header {hash} doesn't connect"))?
+                        + 1
+                }
+            };
+            let expected = blockchain
+                .bits_along(height, |height| {
+                    sync.ancestor(&blockchain, header.prev, height)
+                })
+                .with_context(|| format!("header {hash} doesn't connect"))?;
+            blockchain
+                .check_header(height, header, expected)
+                .context("peer sent a bad header")?;
+            if !blockchain.contains(&hash) {
+                sync.add_header(hash, header, height, peer.addr);
+            }
+            Ok(())
+        })
+    }
+
+    /// Accepts a block from `peer` along with any of its stashed
+    /// descendants, relaying those that extend the canonical chain to the
+    /// other peers. A block whose parent is missing waits for it if that's
+    /// being synced, and otherwise makes us ask `peer` for headers.
+    fn receive(&self, peer: &Peer, block: Block) -> anyhow::Result<()> {
+        let mut blockchain = self.blockchain();
+        let mut sync = self.sync();
+        let hash = blockchain.hash_block(&block);
+        let wanted = sync.delivered(&hash);
+        if let Some(prev) = block.prev.filter(|prev| !blockchain.contains(prev)) {
+            if wanted || sync.is_wanted(&prev) {
+                sync.stash(block);
+                return Ok(());
+            }
+            let locator = locator(&blockchain);
+            drop((blockchain, sync));
+            return Ok(peer.send(&Message::GetHeaders(locator))?);
+        }
+        let mut connected = Vec::new();
+        let mut next = Some(block);
+        while let Some(block) = next {
+            let hash = blockchain.hash_block(&block);
+            match blockchain.accept(block) {
+                Ok(reorg) => {
+                    self.mempool().update(&reorg, blockchain.accounts());
+                    connected.extend(reorg.connected);
+                }
+                Err(error) if matches!(error.downcast_ref(), Some(BlockError::Known(_))) => {}
+                Err(error) => {
+                    // The rest of the synced branch builds on this block.
+                    sync.clear();
+                    let locator = locator(&blockchain);
+                    drop((blockchain, sync));
+                    self.broadcast(&Message::GetHeaders(locator), Some(peer.addr));
+                    return Err(match error.is::<ValidationError>() {
+                        true => error.context("peer sent an invalid block"),
+                        false => error,
+                    });
+                }
+            }
+            next = sync.take_child(&hash);
+            if let Some(child) = &next {
+                sync.delivered(&blockchain.hash_block(child));
+            }
+        }
+        let Some(tip) = connected.last() else {
+            return Ok(());
+        };
+        self.emit(Event::Tip {
+            height: blockchain.len() - 1,
+            hash: blockchain.hash_block(tip),
+            from: peer.addr,
+        });
+        let hashes: Vec<_> = connected
+            .iter()
+            .rev()
+            .take(MAX_INV)
+            .rev()
+            .map(|block| blockchain.hash_block(block))
+            .collect();
+        drop((blockchain, sync));
+        self.broadcast(&Message::Inv(hashes), Some(peer.addr));
+        Ok(())
+    }
+
+    /// Asks peers for the next bodies to sync.
+    fn request_bodies(&self) {
+        let requests = self.sync().schedule(Instant::now());
+        let peers = self.peer_list();
+        for (addr, hashes) in requests {
+            let Some(peer) = peers.iter().find(|peer| peer.addr == addr) else {
+                continue;
+            };
+            if let Err(error) = peer.send(&Message::GetData(hashes)) {
+                self.drop_peer(peer, error);
+            }
+        }
+    }
+
+    fn broadcast(&self, message: &Message, except: Option<SocketAddr>) {
+        self.peer_list()
+            .into_iter()
+            .filter(|peer| Some(peer.addr) != except)
+            .for_each(|peer| {
+                if let Err(error) = peer.send(message) {
+                    self.drop_peer(&peer, error);
+                }
+            });
+    }
+
+    fn drop_peer(&self, peer: &Arc<Peer>, error: io::Error) {
+        self.emit(Event::Disconnected {
+            addr: peer.addr,
+            error: Some(error.into()),
+        });
+        self.disconnect(peer);
+    }
+
+    fn disconnect(&self, peer: &Arc<Peer>) {
+        self.shared
+            .peers
+            .lock()
+            .expect("peers lock poisoned")
+            .retain(|other| !Arc::ptr_eq(other, peer));
+        self.sync().drop_peer(peer.addr);
+        // Also ends the peer's serve loop, if it's still reading.
+        let _ = peer
+            .writer
+            .lock()
+            .expect("peer lock poisoned")
+            .shutdown(Shutdown::Both);
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(sender) = &*self.shared.events.lock().expect("events lock poisoned") {
+            // The receiver may be gone, which only means nobody's listening.
+            let _ = sender.send(event);
+        }
+    }
+
+    fn mempool(&self) -> MutexGuard<'_, Mempool> {
+        self.shared.mempool.lock().expect("mempool lock poisoned")
+    }
+
+    fn sync(&self) -> MutexGuard<'_, HeaderSync> {
+        self.shared.sync.lock().expect("sync lock poisoned")
+    }
+
+    fn peer_list(&self) -> Vec<Arc<Peer>> {
+        self.shared
+            .peers
+            .lock()
+            .expect("peers lock poisoned")
+            .clone()
+    }
+}
+
+/// Canonical hashes from the tip back to genesis, one per block for the
+/// first few and then at doubling gaps, so a peer can find where its chain
+/// and ours diverge.
+fn locator(blockchain: &Blockchain) -> Vec<BlockHash> {
+    let Some(mut height) = blockchain.len().checked_sub(1) else {
+        return Vec::new();
+    };
+    let mut locator = Vec::new();
+    let mut step = 1;
+    loop {
+        locator.push(blockchain.hash_at(height).expect("height below len"));
+        if height == 0 {
+            return locator;
+        }
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+}
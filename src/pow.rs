@@ -0,0 +1,260 @@
+use core::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sha2::{digest::Output, Digest, Sha256};
+
+use crate::{hash::Hex, Block, BlockHash};
+
+/// SHA-256 state after absorbing everything in a block header except the
+/// nonce, so each mining attempt only hashes the final 8 bytes.
+#[derive(Clone)]
+pub struct Midstate(Sha256);
+
+impl Midstate {
+    pub fn new(block: &Block) -> Self {
+        Self(Sha256::new().chain_update(block.header().prefix()))
+    }
+
+    pub fn digest(&self, nonce: u64) -> BlockHash {
+        let mut digest = Output::<Sha256>::default();
+        self.0
+            .clone()
+            .chain_update(nonce.to_le_bytes())
+            .finalize_into(&mut digest);
+        BlockHash(digest.into())
+    }
+}
+
+/// 256-bit big-endian number a block hash, read as a big-endian integer,
+/// must not exceed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target([u8; 32]);
+
+impl Target {
+    pub const MAX: Self = Self([0xff; 32]);
+
+    /// The smallest target a hash can still meet, 1.
+    pub const MIN: Self = {
+        let mut target = [0; 32];
+        target[31] = 1;
+        Self(target)
+    };
+
+    /// The largest target whose hashes start with `bits` zero bits.
+    pub fn from_leading_zeros(bits: u32) -> Self {
+        let mut target = [0xff; 32];
+        let (bytes, rest) = ((bits / 8) as usize, bits % 8);
+        target.iter_mut().take(bytes).for_each(|byte| *byte = 0);
+        if let Some(byte) = target.get_mut(bytes) {
+            *byte >>= rest;
+        }
+        Self(target)
+    }
+
+    /// Decodes the compact "bits" form stored in blocks: the top byte is the
+    /// length of the number in bytes and the low 3 bytes are its most
+    /// significant bytes, as in Bitcoin's `nBits`.
+    pub fn from_compact(bits: u32) -> anyhow::Result<Self> {
+        if bits & 0x0080_0000 != 0 {
+            anyhow::bail!("negative compact target: {bits:#010x}");
+        }
+        let size = (bits >> 24) as isize;
+        let mut target = [0; 32];
+        for (i, &byte) in bits.to_be_bytes()[1..].iter().enumerate() {
+            match 32 - size + i as isize {
+                index @ 0..32 => target[index as usize] = byte,
+                ..0 if byte != 0 => anyhow::bail!("compact target overflows: {bits:#010x}"),
+                _ => {}
+            }
+        }
+        Ok(Self(target))
+    }
+
+    /// Encodes the target in compact form, dropping all but its 3 most
+    /// significant bytes.
+    pub fn to_compact(self) -> u32 {
+        let Some(start) = self.0.iter().position(|&byte| byte != 0) else {
+            return 0;
+        };
+        let mut size = (32 - start) as u32;
+        let mut mantissa = (start..start + 3).fold(0, |mantissa, i| {
+            mantissa << 8 | u32::from(self.0.get(i).copied().unwrap_or_default())
+        });
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        size << 24 | mantissa
+    }
+
+    pub fn is_met_by(&self, hash: &BlockHash) -> bool {
+        hash.as_bytes() <= &self.0
+    }
+
+    /// Multiplies the target by `numerator / denominator`, saturating at
+    /// [`Target::MAX`] and never going below [`Target::MIN`].
+    pub fn scale(self, numerator: u64, denominator: u64) -> Self {
+        let limbs = self
+            .0
+            .chunks_exact(8)
+            .map(|limb| u64::from_be_bytes(limb.try_into().expect("8-byte limb")) as u128);
+        let mut product = [0u64; 5];
+        let carry = limbs.enumerate().rev().fold(0, |carry, (i, limb)| {
+            let value = limb * numerator as u128 + carry;
+            product[i + 1] = value as u64;
+            value >> 64
+        });
+        product[0] = carry as u64;
+        product.iter_mut().fold(0, |remainder, limb| {
+            let value = remainder << 64 | *limb as u128;
+            *limb = (value / denominator as u128) as u64;
+            value % denominator as u128
+        });
+        if product[0] != 0 {
+            return Self::MAX;
+        }
+        let mut target = [0; 32];
+        target
+            .chunks_exact_mut(8)
+            .zip(&product[1..])
+            .for_each(|(bytes, limb)| bytes.copy_from_slice(&limb.to_be_bytes()));
+        Self(target).max(Self::MIN)
+    }
+
+    /// Expected number of hashes needed to meet the target, 2^256 divided by
+    /// one more than it. Computed from its 64 most significant bits and
+    /// saturating at `u128::MAX`, which only targets below 2^128 reach.
+    pub fn work(&self) -> u128 {
+        let (high, low) = self.0.split_at(16);
+        let high = u128::from_be_bytes(high.try_into().expect("16-byte half"));
+        let low = u128::from_be_bytes(low.try_into().expect("16-byte half"));
+        if high == 0 {
+            return u128::MAX;
+        }
+        // The target is just under `divisor * 2^shift`, with `shift` > 64.
+        let shift = 192 - high.leading_zeros();
+        let top = match shift {
+            128.. => high >> (shift - 128),
+            _ => high << (128 - shift) | low >> shift,
+        };
+        let divisor = top + 1;
+        match 256 - shift {
+            exponent @ ..128 => (1 << exponent) / divisor,
+            exponent => ((1 << 127) / divisor) << (exponent - 127),
+        }
+    }
+}
+
+/// Every `interval` blocks, the target is scaled by how long the previous
+/// `interval` blocks actually took compared to `spacing` per block, by at
+/// most a factor of `max_adjustment` either way and never above the chain's
+/// initial target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retarget {
+    pub interval: usize,
+    pub spacing: Duration,
+    pub max_adjustment: u32,
+}
+
+/// Why [`Retarget::validate`] rejected a set of rules.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum RetargetError {
+    #[error("retarget interval must be at least 2, not {0}")]
+    Interval(usize),
+    #[error("retarget adjustment must be at least 1")]
+    Adjustment,
+    #[error("retarget spacing must be at least a millisecond, not {0:?}")]
+    SpacingTooShort(Duration),
+    #[error("retarget spacing of {0:?} doesn't fit in 64-bit nanoseconds")]
+    SpacingTooLong(Duration),
+}
+
+impl Retarget {
+    /// Checks that the interval spans at least one block time, the
+    /// adjustment is at least 1 and the spacing is at least a millisecond
+    /// and fits in `u64` nanoseconds, as chain files store it.
+    pub fn validate(&self) -> Result<(), RetargetError> {
+        if self.interval < 2 {
+            return Err(RetargetError::Interval(self.interval));
+        }
+        if self.max_adjustment < 1 {
+            return Err(RetargetError::Adjustment);
+        }
+        if self.spacing.as_millis() < 1 {
+            return Err(RetargetError::SpacingTooShort(self.spacing));
+        }
+        if u64::try_from(self.spacing.as_nanos()).is_err() {
+            return Err(RetargetError::SpacingTooLong(self.spacing));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Hex(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::Blockchain;
+
+    fn retarget(interval: usize, spacing: Duration, max_adjustment: u32) -> Retarget {
+        Retarget {
+            interval,
+            spacing,
+            max_adjustment,
+        }
+    }
+
+    #[test]
+    fn retarget_rules_are_validated() {
+        let second = Duration::from_secs(1);
+        let (short, long) = (
+            Duration::from_micros(999),
+            Duration::from_secs(u64::MAX / 1000),
+        );
+        assert_eq!(retarget(2, second, 1).validate(), Ok(()));
+        assert_eq!(
+            retarget(2, Duration::from_nanos(u64::MAX), 1).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            retarget(1, second, 1).validate(),
+            Err(RetargetError::Interval(1))
+        );
+        assert_eq!(
+            retarget(2, second, 0).validate(),
+            Err(RetargetError::Adjustment)
+        );
+        assert_eq!(
+            retarget(2, short, 1).validate(),
+            Err(RetargetError::SpacingTooShort(short))
+        );
+        assert_eq!(
+            retarget(2, long, 1).validate(),
+            Err(RetargetError::SpacingTooLong(long))
+        );
+    }
+
+    #[test]
+    fn longest_spacing_retargets_without_overflow() {
+        let mut blockchain =
+            Blockchain::new(1).with_retarget(retarget(3, Duration::from_nanos(u64::MAX), 1));
+        (0..7).for_each(|_| blockchain.add(Vec::new()).unwrap());
+        assert!(blockchain.verify_chain().is_ok());
+    }
+
+    #[test]
+    fn scale_stays_between_min_and_max() {
+        let target = Target::from_leading_zeros(8);
+        assert_eq!(target.scale(0, 1), Target::MIN);
+        assert_eq!(target.scale(u64::MAX, 1), Target::MAX);
+        assert_eq!(target.scale(3, 3), target);
+    }
+}
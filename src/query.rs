@@ -0,0 +1,64 @@
+use std::ops::{Bound, RangeBounds};
+
+use chrono::{DateTime, Utc};
+
+use crate::{Block, BlockHash, Blockchain, Transaction};
+
+/// A block found in the chain, along with where it sits and its hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRef<'a> {
+    pub height: usize,
+    pub hash: BlockHash,
+    pub block: &'a Block,
+}
+
+impl Blockchain {
+    pub fn get(&self, height: usize) -> Option<BlockRef<'_>> {
+        (height < self.blocks.len()).then(|| self.block_ref(height))
+    }
+
+    pub fn get_by_hash(&self, hash: &BlockHash) -> Option<BlockRef<'_>> {
+        self.height_of(hash).map(|height| self.block_ref(height))
+    }
+
+    /// Blocks whose timestamp falls within `range`, oldest first. Timestamps
+    /// never decrease along a valid chain, so the ends are found by binary
+    /// search.
+    pub fn between(
+        &self,
+        range: impl RangeBounds<DateTime<Utc>>,
+    ) -> impl DoubleEndedIterator<Item = BlockRef<'_>> + ExactSizeIterator {
+        let start = match range.start_bound() {
+            Bound::Included(start) => self.blocks.partition_point(|b| b.timestamp() < *start),
+            Bound::Excluded(start) => self.blocks.partition_point(|b| b.timestamp() <= *start),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => self.blocks.partition_point(|b| b.timestamp() <= *end),
+            Bound::Excluded(end) => self.blocks.partition_point(|b| b.timestamp() < *end),
+            Bound::Unbounded => self.blocks.len(),
+        };
+        (start..end.max(start)).map(|height| self.block_ref(height))
+    }
+
+    /// Blocks holding at least one transaction that satisfies `predicate`,
+    /// oldest first.
+    pub fn containing(
+        &self,
+        mut predicate: impl FnMut(&Transaction) -> bool,
+    ) -> impl Iterator<Item = BlockRef<'_>> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(move |(_, block)| block.transactions.iter().any(&mut predicate))
+            .map(|(height, _)| self.block_ref(height))
+    }
+
+    fn block_ref(&self, height: usize) -> BlockRef<'_> {
+        BlockRef {
+            height,
+            hash: self.hashes[height],
+            block: &self.blocks[height],
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{transaction::MAX_MEMO_LEN, Block, Blockchain, Transaction};
+
+/// Coins each block may mint for its miner: `initial` at first, halving
+/// every `halving_interval` blocks, and nothing more once `max_supply` coins
+/// have been minted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subsidy {
+    pub initial: u64,
+    pub halving_interval: usize,
+    pub max_supply: u64,
+}
+
+/// 50 coins of 10^8 units each, halving every 210,000 blocks, up to 21
+/// million coins.
+impl Default for Subsidy {
+    fn default() -> Self {
+        Self {
+            initial: 5_000_000_000,
+            halving_interval: 210_000,
+            max_supply: 2_100_000_000_000_000,
+        }
+    }
+}
+
+impl Subsidy {
+    /// What the block at `height` may mint.
+    pub fn at(&self, height: usize) -> u64 {
+        self.issued(height.saturating_add(1)) - self.issued(height)
+    }
+
+    /// Coins minted by the `len` blocks from genesis up.
+    pub fn issued(&self, len: usize) -> u64 {
+        let (len, interval) = (len as u128, self.halving_interval as u128);
+        let scheduled: u128 = (0..u64::BITS)
+            .map(|halvings| {
+                let start = (halvings as u128).saturating_mul(interval);
+                let blocks = len.saturating_sub(start).min(interval);
+                (self.initial >> halvings) as u128 * blocks
+            })
+            .sum();
+        scheduled.min(self.max_supply as u128) as u64
+    }
+}
+
+/// Why a block's coinbase is invalid.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CoinbaseError {
+    #[error("block doesn't start with a coinbase")]
+    Missing,
+    #[error("transaction {0} is a coinbase but isn't first")]
+    Misplaced(usize),
+    #[error("coinbase must have no inputs, fee or signature, and the block height as its nonce")]
+    Malformed,
+    #[error("coinbase claims {claimed}, more than the {allowed} allowed")]
+    Overclaimed { claimed: u64, allowed: u64 },
+}
+
+impl Blockchain {
+    /// The most the coinbase of a block at `height` with these other
+    /// transactions may claim: the subsidy plus their fees.
+    pub fn reward(&self, height: usize, transactions: &[Transaction]) -> u64 {
+        transactions
+            .iter()
+            .fold(self.subsidy.at(height), |reward, transaction| {
+                reward.saturating_add(transaction.fee)
+            })
+    }
+
+    pub(crate) fn check_coinbase(&self, height: usize, block: &Block) -> Result<(), CoinbaseError> {
+        let (coinbase, rest) = block
+            .transactions
+            .split_first()
+            .filter(|(first, _)| first.is_coinbase())
+            .ok_or(CoinbaseError::Missing)?;
+        if let Some(index) = rest.iter().position(Transaction::is_coinbase) {
+            return Err(CoinbaseError::Misplaced(index + 1));
+        }
+        if !coinbase.inputs.is_empty()
+            || coinbase.fee != 0
+            || coinbase.signature.is_some()
+            || coinbase.nonce != height as u64
+            || coinbase.memo.len() > MAX_MEMO_LEN
+        {
+            return Err(CoinbaseError::Malformed);
+        }
+        let allowed = self.reward(height, rest);
+        if coinbase.amount > allowed {
+            return Err(CoinbaseError::Overclaimed {
+                claimed: coinbase.amount,
+                allowed,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, OutPoint, SigningKey};
+
+    #[test]
+    fn subsidy_halves_at_interval_boundaries() {
+        let subsidy = Subsidy {
+            initial: 100,
+            halving_interval: 3,
+            max_supply: u64::MAX,
+        };
+        let rewards: Vec<_> = (0..10).map(|height| subsidy.at(height)).collect();
+        assert_eq!(rewards, [100, 100, 100, 50, 50, 50, 25, 25, 25, 12]);
+        assert_eq!(subsidy.issued(0), 0);
+        assert_eq!(subsidy.issued(4), 350);
+        assert_eq!(subsidy.at(3 * 7), 0);
+        assert_eq!(
+            subsidy.issued(usize::MAX),
+            (100 + 50 + 25 + 12 + 6 + 3 + 1) * 3
+        );
+
+        let default = Subsidy::default();
+        assert_eq!(default.at(209_999), 5_000_000_000);
+        assert_eq!(default.at(210_000), 2_500_000_000);
+        assert_eq!(default.issued(usize::MAX), 2_099_999_997_690_000);
+    }
+
+    #[test]
+    fn subsidy_stops_part_way_through_the_block_reaching_the_cap() {
+        let subsidy = Subsidy {
+            initial: 100,
+            halving_interval: 10,
+            max_supply: 250,
+        };
+        let rewards: Vec<_> = (0..4).map(|height| subsidy.at(height)).collect();
+        assert_eq!(rewards, [100, 100, 50, 0]);
+        assert_eq!(subsidy.issued(usize::MAX), 250);
+    }
+
+    #[test]
+    fn subsidy_halves_every_block_with_an_interval_of_one() {
+        let subsidy = Subsidy {
+            initial: 8,
+            halving_interval: 1,
+            max_supply: u64::MAX,
+        };
+        let rewards: Vec<_> = (0..6).map(|height| subsidy.at(height)).collect();
+        assert_eq!(rewards, [8, 4, 2, 1, 0, 0]);
+        assert_eq!(subsidy.issued(usize::MAX), 15);
+        assert_eq!(subsidy.at(usize::MAX), 0);
+    }
+
+    fn transfer(fee: u64) -> Transaction {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        Transaction {
+            sender: Address::of(&key),
+            recipient: Address::COINBASE,
+            amount: 1,
+            fee,
+            nonce: 0,
+            inputs: Vec::new(),
+            memo: String::new(),
+            signature: None,
+        }
+        .sign(&key)
+    }
+
+    /// The chain's next block, paying its miner `claimed`, with `rest`
+    /// after the coinbase.
+    fn block(blockchain: &Blockchain, claimed: u64, rest: Vec<Transaction>) -> Block {
+        let mut block = blockchain.template(Vec::new()).unwrap();
+        block.transactions[0].amount = claimed;
+        block.transactions.extend(rest);
+        block
+    }
+
+    #[test]
+    fn coinbase_may_claim_the_subsidy_plus_fees() {
+        let mut blockchain =
+            Blockchain::new(4).with_miner(Address::of(&SigningKey::from_bytes(&[2; 32])));
+        blockchain.add(Vec::new()).unwrap();
+        let subsidy = blockchain.subsidy.at(1);
+        for claimed in [0, subsidy, subsidy + 7] {
+            let block = block(&blockchain, claimed, vec![transfer(7)]);
+            assert_eq!(blockchain.check_coinbase(1, &block), Ok(()));
+        }
+        let block = block(&blockchain, subsidy + 8, vec![transfer(7)]);
+        assert_eq!(
+            blockchain.check_coinbase(1, &block),
+            Err(CoinbaseError::Overclaimed {
+                claimed: subsidy + 8,
+                allowed: subsidy + 7
+            })
+        );
+    }
+
+    #[test]
+    fn missing_and_misplaced_coinbases_are_rejected() {
+        let blockchain = Blockchain::new(4);
+        let mut block = block(&blockchain, 0, Vec::new());
+        let coinbase = block.transactions[0].clone();
+
+        block.transactions = Vec::new();
+        assert_eq!(
+            blockchain.check_coinbase(0, &block),
+            Err(CoinbaseError::Missing)
+        );
+        block.transactions = vec![transfer(0), coinbase.clone()];
+        assert_eq!(
+            blockchain.check_coinbase(0, &block),
+            Err(CoinbaseError::Missing)
+        );
+        block.transactions = vec![coinbase.clone(), transfer(0), coinbase];
+        assert_eq!(
+            blockchain.check_coinbase(0, &block),
+            Err(CoinbaseError::Misplaced(2))
+        );
+    }
+
+    #[test]
+    fn malformed_coinbases_are_rejected() {
+        let blockchain = Blockchain::new(4);
+        let malformations: [fn(&mut Transaction); 5] = [
+            |coinbase| coinbase.fee = 1,
+            |coinbase| coinbase.nonce = 1,
+            |coinbase| coinbase.signature = transfer(0).signature,
+            |coinbase| {
+                coinbase.inputs = vec![OutPoint {
+                    tx: [0; 32],
+                    index: 0,
+                }]
+            },
+            |coinbase| coinbase.memo = "x".repeat(MAX_MEMO_LEN + 1),
+        ];
+        for malform in malformations {
+            let mut block = block(&blockchain, 0, Vec::new());
+            malform(&mut block.transactions[0]);
+            assert_eq!(
+                blockchain.check_coinbase(0, &block),
+                Err(CoinbaseError::Malformed)
+            );
+        }
+    }
+}
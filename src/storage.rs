@@ -0,0 +1,308 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    codec::{self, DecodeError, Reader},
+    pow::Retarget,
+    Block, StateModel, Subsidy,
+};
+
+/// Identifies the header record a chain file starts with.
+const MAGIC: &[u8] = b"pz1 chain";
+
+/// The difficulty, subsidy and state rules a chain was created with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ChainParams {
+    pub bits: u32,
+    pub retarget: Option<Retarget>,
+    pub subsidy: Subsidy,
+    pub state: StateModel,
+}
+
+impl ChainParams {
+    /// A header record with the magic bytes, the `u32` initial bits and a
+    /// `u8` retarget flag, followed when set by the `u64` interval, the
+    /// `u64` spacing in nanoseconds and the `u32` maximum adjustment, then
+    /// the `u64` initial subsidy, halving interval and maximum supply, and
+    /// the state model as a `u8`, 0 for UTXOs and 1 for accounts.
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = MAGIC.to_vec();
+        payload.extend(self.bits.to_le_bytes());
+        match self.retarget {
+            Some(retarget) => {
+                payload.push(1);
+                payload.extend((retarget.interval as u64).to_le_bytes());
+                let spacing = u64::try_from(retarget.spacing.as_nanos())
+                    .expect("validated retarget spacing fits in u64 nanoseconds");
+                payload.extend(spacing.to_le_bytes());
+                payload.extend(retarget.max_adjustment.to_le_bytes());
+            }
+            None => payload.push(0),
+        }
+        payload.extend(self.subsidy.initial.to_le_bytes());
+        payload.extend((self.subsidy.halving_interval as u64).to_le_bytes());
+        payload.extend(self.subsidy.max_supply.to_le_bytes());
+        payload.push(match self.state {
+            StateModel::Utxo => 0,
+            StateModel::Accounts => 1,
+        });
+        codec::encode_record(&payload)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        let (payload, rest) = codec::decode_record(bytes)?;
+        let mut reader = Reader(payload);
+        if reader.take("magic", MAGIC.len()) != Ok(MAGIC) {
+            return Err(DecodeError::BadMagic);
+        }
+        let bits = reader.u32("bits")?;
+        let retarget = match reader.u8("retarget flag")? {
+            0 => None,
+            1 => Some(Retarget {
+                interval: u64::from_le_bytes(reader.array("interval")?) as usize,
+                spacing: Duration::from_nanos(u64::from_le_bytes(reader.array("spacing")?)),
+                max_adjustment: reader.u32("max adjustment")?,
+            }),
+            value => {
+                return Err(DecodeError::InvalidFlag {
+                    field: "retarget",
+                    value,
+                })
+            }
+        };
+        let subsidy = Subsidy {
+            initial: u64::from_le_bytes(reader.array("initial subsidy")?),
+            halving_interval: u64::from_le_bytes(reader.array("halving interval")?) as usize,
+            max_supply: u64::from_le_bytes(reader.array("max supply")?),
+        };
+        let state = match reader.u8("state model")? {
+            0 => StateModel::Utxo,
+            1 => StateModel::Accounts,
+            value => {
+                return Err(DecodeError::InvalidFlag {
+                    field: "state model",
+                    value,
+                })
+            }
+        };
+        if !reader.0.is_empty() {
+            return Err(DecodeError::TrailingBytes {
+                field: "chain header",
+                len: reader.0.len(),
+            });
+        }
+        Ok((
+            Self {
+                bits,
+                retarget,
+                subsidy,
+                state,
+            },
+            rest,
+        ))
+    }
+}
+
+/// A header record with the chain's parameters followed by every block
+/// record in chain order, as stored in chain files.
+pub(crate) fn encode_chain(params: &ChainParams, blocks: &[Block]) -> Vec<u8> {
+    let mut bytes = params.encode();
+    blocks.iter().for_each(|block| bytes.extend(block.encode()));
+    bytes
+}
+
+pub(crate) fn decode_chain(bytes: &[u8]) -> anyhow::Result<(ChainParams, Vec<Block>)> {
+    let (params, blocks, _) = decode_records(bytes, false)?;
+    Ok((params, blocks))
+}
+
+/// Decodes the records of a chain file, returning how many bytes they take
+/// up. With `torn_tail`, a last record cut short, as a crash mid-append
+/// leaves it, ends the chain instead of failing.
+fn decode_records(
+    bytes: &[u8],
+    torn_tail: bool,
+) -> anyhow::Result<(ChainParams, Vec<Block>, usize)> {
+    let (params, mut rest) = ChainParams::decode(bytes).context("malformed chain header")?;
+    let mut blocks = Vec::new();
+    while !rest.is_empty() {
+        if torn_tail
+            && matches!(
+                codec::decode_record(rest),
+                Err(DecodeError::Truncated { .. })
+            )
+        {
+            break;
+        }
+        let (n, offset) = (blocks.len(), bytes.len() - rest.len());
+        let (block, tail) = Block::decode(rest)
+            .with_context(|| format!("malformed record {n} at offset {offset}"))?;
+        match (n, &block.prev) {
+            (0, Some(_)) => anyhow::bail!("record {n}: genesis block has a prev hash"),
+            (1.., None) => anyhow::bail!("record {n}: missing prev hash"),
+            _ => blocks.push(block),
+        }
+        rest = tail;
+    }
+    Ok((params, blocks, bytes.len() - rest.len()))
+}
+
+/// Chain file in the format of [`encode_chain`], appended to as blocks are
+/// mined and cut back to the fork point when the chain reorganizes.
+pub struct Storage {
+    file: File,
+    /// Where each block record starts, followed by the end of the file.
+    offsets: Vec<u64>,
+}
+
+impl Storage {
+    /// Opens the chain file at `path`, returning the parameters and blocks
+    /// stored in it. An empty or missing file is initialized with `params`,
+    /// and a block record cut short at the end of the file, left by an
+    /// append that never finished, is cut off.
+    pub fn open(
+        path: impl AsRef<Path>,
+        params: ChainParams,
+    ) -> anyhow::Result<(Self, ChainParams, Vec<Block>)> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("failed to open chain file {path:?}"))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let mut storage = Self {
+            file,
+            offsets: Vec::new(),
+        };
+        if bytes.is_empty() {
+            storage.write(&params.encode())?;
+            return Ok((storage, params, Vec::new()));
+        }
+        let (params, blocks, complete) =
+            decode_records(&bytes, true).with_context(|| format!("invalid chain file {path:?}"))?;
+        if complete < bytes.len() {
+            storage
+                .file
+                .set_len(complete as u64)
+                .and_then(|()| storage.file.sync_data())
+                .with_context(|| format!("failed to cut the torn record off {path:?}"))?;
+        }
+        storage.offsets = std::iter::once(params.encode().len())
+            .chain(blocks.iter().map(|block| block.encode().len()))
+            .scan(0, |offset, len| {
+                *offset += len as u64;
+                Some(*offset)
+            })
+            .collect();
+        Ok((storage, params, blocks))
+    }
+
+    /// Writes a new chain file at `path`, failing if one already exists.
+    pub fn create(
+        path: impl AsRef<Path>,
+        params: ChainParams,
+        blocks: &[Block],
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("failed to create chain file {path:?}"))?;
+        let mut storage = Self {
+            file,
+            offsets: Vec::new(),
+        };
+        storage.write(&params.encode())?;
+        blocks.iter().try_for_each(|block| storage.append(block))?;
+        Ok(storage)
+    }
+
+    pub fn append(&mut self, block: &Block) -> anyhow::Result<()> {
+        self.write(&block.encode())
+    }
+
+    /// Drops every block from `height` up.
+    pub fn truncate(&mut self, height: usize) -> anyhow::Result<()> {
+        self.offsets.truncate(height + 1);
+        let len = self.offsets[height];
+        self.file
+            .set_len(len)
+            .context("failed to truncate chain file")?;
+        self.file.sync_data().context("failed to sync chain file")
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(bytes)?;
+        self.file.sync_data().context("failed to sync chain file")?;
+        let end = self.offsets.last().unwrap_or(&0) + bytes.len() as u64;
+        self.offsets.push(end);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::Blockchain;
+
+    fn chain_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pz1-{}-{name}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut blockchain = Blockchain::new(4);
+        blockchain.save(&path).unwrap();
+        (0..3).for_each(|_| blockchain.add(Vec::new()).unwrap());
+        path
+    }
+
+    #[test]
+    fn torn_append_is_cut_off() {
+        let path = chain_file("torn");
+        let len = fs::metadata(&path).unwrap().len();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let mut blockchain = Blockchain::new(0).open(&path).unwrap();
+        assert_eq!(blockchain.len(), 2);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            blockchain.to_bytes().len() as u64
+        );
+        blockchain.add(Vec::new()).unwrap();
+        assert_eq!(Blockchain::new(0).open(&path).unwrap().len(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_record_is_an_error() {
+        let path = chain_file("corrupted");
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(Blockchain::new(0).open(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_import_is_an_error() {
+        let path = chain_file("import");
+        let bytes = fs::read(&path).unwrap();
+        assert!(Blockchain::from_bytes(&bytes[..bytes.len() - 3]).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{Block, BlockHash, Blockchain, Header};
+
+/// Most bodies requested from one peer at a time.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Most queued bodies passed over for want of an idle peer that has them
+/// before scheduling gives up, which keeps it cheap while every peer is busy.
+const MAX_SKIPPED: usize = 1024;
+
+/// How long a peer has to deliver a requested body before it's asked of
+/// another peer instead.
+const BODY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Headers-first download: headers are checked ahead of the chain, then
+/// their bodies are requested lowest first from whichever peers sent them,
+/// spread across those peers, and handed to the chain as their parents
+/// arrive. Only accepted blocks are persisted, so a restarted node resumes
+/// by asking for the headers after its tip again.
+#[derive(Default)]
+pub(crate) struct HeaderSync {
+    /// Checked headers whose bodies haven't been accepted yet.
+    wanted: HashMap<BlockHash, Wanted>,
+    /// Wanted bodies not yet asked for, lowest first, including some since
+    /// delivered anyway.
+    queue: VecDeque<BlockHash>,
+    /// Who each requested body was asked of, and when.
+    in_flight: HashMap<BlockHash, (SocketAddr, Instant)>,
+    /// Bodies that arrived before their parent, by parent hash.
+    early: HashMap<BlockHash, Block>,
+}
+
+struct Wanted {
+    height: usize,
+    prev: Option<BlockHash>,
+    /// The header's timestamp and bits, which later headers' targets depend
+    /// on.
+    timestamp: i64,
+    bits: u32,
+    /// Peers that sent the header, so should have the body.
+    sources: Vec<SocketAddr>,
+}
+
+impl HeaderSync {
+    /// Height of a block whose header is being synced.
+    pub fn height(&self, hash: &BlockHash) -> Option<usize> {
+        self.wanted.get(hash).map(|wanted| wanted.height)
+    }
+
+    pub fn is_wanted(&self, hash: &BlockHash) -> bool {
+        self.wanted.contains_key(hash)
+    }
+
+    /// Timestamp and bits of the block at `height` on the branch ending at
+    /// `tip`, whether its header is being synced or the chain has it.
+    pub fn ancestor(
+        &self,
+        blockchain: &Blockchain,
+        tip: Option<BlockHash>,
+        height: usize,
+    ) -> Option<(i64, u32)> {
+        let mut cursor = tip?;
+        while let Some(wanted) = self.wanted.get(&cursor) {
+            if wanted.height <= height {
+                return (wanted.height == height).then_some((wanted.timestamp, wanted.bits));
+            }
+            cursor = wanted.prev?;
+        }
+        blockchain
+            .ancestor(Some(cursor), height)
+            .map(|block| (block.timestamp, block.bits))
+    }
+
+    /// Records that `peer` sent the checked header of a block at `height`
+    /// the chain doesn't have.
+    pub fn add_header(
+        &mut self,
+        hash: BlockHash,
+        header: &Header,
+        height: usize,
+        peer: SocketAddr,
+    ) {
+        let wanted = self.wanted.entry(hash).or_insert_with(|| {
+            self.queue.push_back(hash);
+            Wanted {
+                height,
+                prev: header.prev,
+                timestamp: header.timestamp,
+                bits: header.bits,
+                sources: Vec::new(),
+            }
+        });
+        if !wanted.sources.contains(&peer) {
+            wanted.sources.push(peer);
+        }
+    }
+
+    /// Stops waiting for a body, returning whether it was wanted.
+    pub fn delivered(&mut self, hash: &BlockHash) -> bool {
+        self.in_flight.remove(hash);
+        self.wanted.remove(hash).is_some()
+    }
+
+    /// Holds on to a body until its parent is accepted.
+    pub fn stash(&mut self, block: Block) {
+        if let Some(prev) = block.prev {
+            self.early.insert(prev, block);
+        }
+    }
+
+    /// The stashed body whose parent is `parent`.
+    pub fn take_child(&mut self, parent: &BlockHash) -> Option<Block> {
+        self.early.remove(parent)
+    }
+
+    /// Stops expecting anything from a peer that's gone, queueing what was
+    /// asked of it again.
+    pub fn drop_peer(&mut self, peer: SocketAddr) {
+        self.wanted
+            .values_mut()
+            .for_each(|wanted| wanted.sources.retain(|source| *source != peer));
+        self.requeue(|requested, _| requested == peer);
+    }
+
+    /// Starts over, e.g. after a body turned out invalid.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Picks which bodies to ask each peer for next: those not already on
+    /// their way, lowest first, each from the least busy peer that has it.
+    /// A peer that let a request time out is no longer asked for that body.
+    pub fn schedule(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<BlockHash>)> {
+        let timed_out = |at: Instant| now.duration_since(at) >= BODY_TIMEOUT;
+        for (hash, (peer, at)) in &self.in_flight {
+            if let Some(wanted) = self.wanted.get_mut(hash).filter(|_| timed_out(*at)) {
+                wanted.sources.retain(|source| source != peer);
+            }
+        }
+        self.requeue(|_, at| timed_out(at));
+        let mut load = HashMap::<SocketAddr, usize>::new();
+        self.in_flight
+            .values()
+            .for_each(|(peer, _)| *load.entry(*peer).or_default() += 1);
+        let mut requests = HashMap::<SocketAddr, Vec<BlockHash>>::new();
+        let mut skipped = VecDeque::new();
+        while skipped.len() < MAX_SKIPPED {
+            let Some(hash) = self.queue.pop_front() else {
+                break;
+            };
+            let Some(wanted) = self.wanted.get(&hash) else {
+                continue;
+            };
+            let busy = |peer: &SocketAddr| load.get(peer).copied().unwrap_or_default();
+            let peer = wanted
+                .sources
+                .iter()
+                .filter(|peer| busy(peer) < MAX_IN_FLIGHT)
+                .min_by_key(|peer| busy(peer))
+                .copied();
+            let Some(peer) = peer else {
+                skipped.push_back(hash);
+                continue;
+            };
+            *load.entry(peer).or_default() += 1;
+            self.in_flight.insert(hash, (peer, now));
+            requests.entry(peer).or_default().push(hash);
+        }
+        skipped.append(&mut self.queue);
+        self.queue = skipped;
+        requests.into_iter().collect()
+    }
+
+    /// Moves the requests matching `expired` back to the front of the queue.
+    fn requeue(&mut self, expired: impl Fn(SocketAddr, Instant) -> bool) {
+        let mut requeued: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (peer, at))| expired(*peer, *at))
+            .map(|(hash, _)| *hash)
+            .collect();
+        requeued.sort_by_key(|hash| self.height(hash));
+        for hash in requeued.into_iter().rev() {
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pow::Retarget;
+
+    #[test]
+    fn header_targets_follow_retargets_past_the_tip() {
+        let retarget = Retarget {
+            interval: 2,
+            spacing: Duration::from_secs(3600),
+            max_adjustment: 4,
+        };
+        let mut source = Blockchain::new(4).with_retarget(retarget);
+        (0..5).for_each(|_| source.add(Vec::new()).unwrap());
+        let mut chain = Blockchain::new(4).with_retarget(retarget);
+        chain.accept(source.iter().next().unwrap().clone()).unwrap();
+
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let mut sync = HeaderSync::default();
+        for (height, block) in source.iter().enumerate().skip(1) {
+            let header = block.header();
+            let expected =
+                chain.bits_along(height, |height| sync.ancestor(&chain, header.prev, height));
+            assert_eq!(expected, Some(header.bits), "height {height}");
+            sync.add_header(header.hash(), &header, height, peer);
+        }
+        assert_ne!(source.tip().unwrap().bits, chain.tip().unwrap().bits);
+
+        let unknown = Some(BlockHash([0; 32]));
+        let expected = chain.bits_along(2, |height| sync.ancestor(&chain, unknown, height));
+        assert_eq!(expected, None);
+    }
+}
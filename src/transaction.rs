@@ -0,0 +1,314 @@
+use core::{fmt, str::FromStr};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    codec::{DecodeError, Reader},
+    hash::{self, Hex},
+    OutPoint,
+};
+
+/// Longest memo, in bytes, a valid transaction may carry.
+pub const MAX_MEMO_LEN: usize = 256;
+
+/// Prefix of the signed message, so a transaction signature can't be
+/// replayed as a signature over anything else.
+const SIGNING_DOMAIN: &[u8] = b"pz1 transaction v2";
+
+/// An Ed25519 public key, which both identifies an account and checks the
+/// signatures on its transactions.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address(pub [u8; 32]);
+
+/// Generates a fresh key pair from the operating system's randomness.
+pub fn generate_key() -> SigningKey {
+    SigningKey::generate(&mut OsRng)
+}
+
+impl Address {
+    /// Sender of every coinbase transaction, a key nobody can sign with.
+    pub const COINBASE: Self = Self([0; 32]);
+
+    pub fn of(key: &SigningKey) -> Self {
+        Self(key.verifying_key().to_bytes())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Hex(&self.0).fmt(f)
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Address({self})")
+    }
+}
+
+/// Serialized as the hex string it displays as.
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        hash::parse_hex(s).map(Self)
+    }
+}
+
+/// Transfer of `amount` from `sender` to `recipient`, paying `fee` to the
+/// miner and signed by the sender's key. `nonce` counts the sender's
+/// transactions so identical transfers stay distinguishable.
+///
+/// On chains following [`StateModel::Utxo`](crate::StateModel::Utxo),
+/// `inputs` are the sender's unspent outputs the transfer is paid from. It
+/// creates up to two outputs of its own: index 0 pays `amount` to the
+/// recipient, and index 1 returns what the inputs hold beyond `amount` and
+/// `fee` to the sender, if anything. The account model ignores them.
+///
+/// Each block starts with a coinbase, see [`Transaction::coinbase`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub sender: Address,
+    pub recipient: Address,
+    pub amount: u64,
+    pub fee: u64,
+    pub nonce: u64,
+    #[serde(default)]
+    pub inputs: Vec<OutPoint>,
+    pub memo: String,
+    #[serde(with = "signature_hex")]
+    pub signature: Option<Signature>,
+}
+
+impl Transaction {
+    /// Pays the reward of the block at `height` to its miner. Unlike other
+    /// transactions it comes from [`Address::COINBASE`], spends nothing and
+    /// is unsigned; the height as its nonce keeps each coinbase unique.
+    pub fn coinbase(recipient: Address, amount: u64, height: usize) -> Self {
+        Self {
+            sender: Address::COINBASE,
+            recipient,
+            amount,
+            fee: 0,
+            nonce: height as u64,
+            inputs: Vec::new(),
+            memo: String::new(),
+            signature: None,
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == Address::COINBASE
+    }
+
+    /// The canonical encoding hashed into blocks: fields in declaration
+    /// order, addresses as their 32 key bytes, integers as little-endian
+    /// `u64`, strings as their UTF-8 bytes prefixed by a little-endian `u32`
+    /// length, inputs as a `u32` count followed by each one's transaction id
+    /// and `u32` output index, and the signature as a presence byte followed
+    /// by its 64 bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.unsigned_bytes();
+        match &self.signature {
+            Some(signature) => {
+                bytes.push(1);
+                bytes.extend(signature.to_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    /// SHA-256 of [`Transaction::to_bytes`], identifying the transaction.
+    pub fn id(&self) -> [u8; 32] {
+        Sha256::digest(self.to_bytes()).into()
+    }
+
+    fn unsigned_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(self.sender.0);
+        bytes.extend(self.recipient.0);
+        bytes.extend(self.amount.to_le_bytes());
+        bytes.extend(self.fee.to_le_bytes());
+        bytes.extend(self.nonce.to_le_bytes());
+        bytes.extend((self.inputs.len() as u32).to_le_bytes());
+        for input in &self.inputs {
+            bytes.extend(input.tx);
+            bytes.extend(input.index.to_le_bytes());
+        }
+        put_str(&mut bytes, &self.memo);
+        bytes
+    }
+
+    /// Decodes [`Transaction::to_bytes`]'s output, naming the field that's
+    /// cut short or malformed if it fails.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader(bytes);
+        let transaction = Self {
+            sender: Address(reader.array("sender")?),
+            recipient: Address(reader.array("recipient")?),
+            amount: reader.u64("amount")?,
+            fee: reader.u64("fee")?,
+            nonce: reader.u64("nonce")?,
+            inputs: (0..reader.u32("input count")?)
+                .map(|_| {
+                    Ok(OutPoint {
+                        tx: reader.array("input transaction id")?,
+                        index: reader.u32("input index")?,
+                    })
+                })
+                .collect::<Result<_, DecodeError>>()?,
+            memo: {
+                let len = reader.u32("memo length")? as usize;
+                String::from_utf8(reader.take("memo", len)?.to_vec())
+                    .map_err(|_| DecodeError::InvalidUtf8 { field: "memo" })?
+            },
+            signature: match reader.u8("signature flag")? {
+                0 => None,
+                1 => Some(Signature::from_bytes(&reader.array("signature")?)),
+                value => {
+                    return Err(DecodeError::InvalidFlag {
+                        field: "signature",
+                        value,
+                    })
+                }
+            },
+        };
+        if !reader.0.is_empty() {
+            return Err(DecodeError::TrailingBytes {
+                field: "transaction",
+                len: reader.0.len(),
+            });
+        }
+        Ok(transaction)
+    }
+
+    /// Signs every other field with `key`, which must be the sender's.
+    pub fn sign(mut self, key: &SigningKey) -> Self {
+        self.signature = Some(key.sign(&self.signing_message()));
+        self
+    }
+
+    fn signing_message(&self) -> Vec<u8> {
+        [SIGNING_DOMAIN, &self.unsigned_bytes()].concat()
+    }
+
+    pub fn verify_signature(&self) -> Result<(), TransactionError> {
+        let signature = self.signature.as_ref().ok_or(TransactionError::Unsigned)?;
+        VerifyingKey::from_bytes(&self.sender.0)
+            .map_err(|_| TransactionError::InvalidSender(self.sender))?
+            .verify_strict(&self.signing_message(), signature)
+            .map_err(|_| TransactionError::BadSignature)
+    }
+
+    pub fn validate(&self) -> Result<(), TransactionError> {
+        if self.sender == self.recipient {
+            return Err(TransactionError::SelfTransfer(self.sender));
+        }
+        if self.amount == 0 {
+            return Err(TransactionError::ZeroAmount);
+        }
+        if self.amount.checked_add(self.fee).is_none() {
+            return Err(TransactionError::Overflow {
+                amount: self.amount,
+                fee: self.fee,
+            });
+        }
+        if self.memo.len() > MAX_MEMO_LEN {
+            return Err(TransactionError::MemoTooLong(self.memo.len()));
+        }
+        self.verify_signature()
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_coinbase() {
+            return write!(f, "coinbase -> {}: {}", self.recipient, self.amount);
+        }
+        write!(
+            f,
+            "{} -> {}: {} (fee {}, nonce {})",
+            self.sender, self.recipient, self.amount, self.fee, self.nonce
+        )?;
+        if !self.memo.is_empty() {
+            write!(f, " {:?}", self.memo)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TransactionError {
+    #[error("sender and recipient are both {0}")]
+    SelfTransfer(Address),
+    #[error("zero amount")]
+    ZeroAmount,
+    #[error("amount {amount} plus fee {fee} overflows")]
+    Overflow { amount: u64, fee: u64 },
+    #[error("memo is {0} bytes, limit is {MAX_MEMO_LEN}")]
+    MemoTooLong(usize),
+    #[error("unsigned transaction")]
+    Unsigned,
+    #[error("sender {0} is not a valid public key")]
+    InvalidSender(Address),
+    #[error("bad signature")]
+    BadSignature,
+}
+
+impl TransactionError {
+    pub fn is_signature_error(&self) -> bool {
+        matches!(
+            self,
+            Self::Unsigned | Self::InvalidSender(_) | Self::BadSignature
+        )
+    }
+}
+
+mod signature_hex {
+    use ed25519_dalek::Signature;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use crate::hash::{self, Hex};
+
+    pub fn serialize<S: Serializer>(
+        signature: &Option<Signature>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match signature {
+            Some(signature) => serializer.collect_str(&Hex(&signature.to_bytes())),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Signature>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|hex| hash::parse_hex(&hex).map(|bytes| Signature::from_bytes(&bytes)))
+            .transpose()
+            .map_err(de::Error::custom)
+    }
+}
+
+fn put_str(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend((s.len() as u32).to_le_bytes());
+    bytes.extend(s.as_bytes());
+}
@@ -0,0 +1,272 @@
+use crate::{Block, BlockHash, Blockchain};
+
+/// A block off the canonical chain, kept in case its branch overtakes it.
+pub(crate) struct Fork {
+    pub block: Block,
+    pub height: usize,
+    /// Cumulative work of the branch up to and including this block.
+    pub work: u128,
+}
+
+/// How accepting a block changed the canonical chain. A block extending the
+/// tip is connected without disconnecting anything, while one extending a
+/// lighter branch changes nothing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Reorg {
+    /// Blocks that left the canonical chain, from the old tip down.
+    pub disconnected: Vec<Block>,
+    /// Blocks that joined the canonical chain, up to the new tip.
+    pub connected: Vec<Block>,
+}
+
+/// Why [`Blockchain::accept`] couldn't place a block in the tree.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BlockError {
+    #[error("block {0} is already known")]
+    Known(BlockHash),
+    /// The block's parent hasn't been seen, so it can't be validated yet.
+    #[error("block {hash} has unknown parent {prev}")]
+    Orphan { hash: BlockHash, prev: BlockHash },
+    #[error("block {0} is the genesis block of another chain")]
+    Genesis(BlockHash),
+}
+
+impl Blockchain {
+    /// Takes in a valid block from elsewhere whose parent is known, on the
+    /// canonical chain or any other branch. Whichever branch has the most
+    /// cumulative work is canonical, the current one winning ties. Only the
+    /// canonical chain is persisted.
+    pub fn accept(&mut self, block: Block) -> anyhow::Result<Reorg> {
+        let hash = self.hash_block(&block);
+        if self.contains(&hash) {
+            return Err(BlockError::Known(hash).into());
+        }
+        let (height, parent_work) = match block.prev {
+            None if self.blocks.is_empty() => (0, 0),
+            None => return Err(BlockError::Genesis(hash).into()),
+            Some(prev) => self
+                .locate(&prev)
+                .map(|(height, work)| (height + 1, work))
+                .ok_or(BlockError::Orphan { hash, prev })?,
+        };
+        let mut faults = Vec::new();
+        let mut ledger = self.ledger_after(block.prev);
+        self.block_faults(height, &block, block.prev, &mut ledger, &mut faults);
+        if let Some(fault) = faults.into_iter().next() {
+            return Err(fault.into());
+        }
+        if block.prev == self.hashes.last().copied() {
+            if let Some(storage) = self.storage.as_mut() {
+                storage.append(&block)?;
+            }
+            self.push(block.clone());
+            return Ok(Reorg {
+                disconnected: Vec::new(),
+                connected: vec![block],
+            });
+        }
+        let work = parent_work.saturating_add(block.work());
+        self.forks.insert(
+            hash,
+            Fork {
+                block,
+                height,
+                work,
+            },
+        );
+        if work <= self.chain_work() {
+            return Ok(Reorg::default());
+        }
+        self.reorganize(hash)
+    }
+
+    /// Whether the block is on the canonical chain or any other branch.
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.heights.contains_key(hash) || self.forks.contains_key(hash)
+    }
+
+    /// Makes the branch ending at `tip` canonical, rolling the chain back to
+    /// where the branch forks off it.
+    fn reorganize(&mut self, tip: BlockHash) -> anyhow::Result<Reorg> {
+        let mut branch = Vec::new();
+        let mut cursor = tip;
+        while let Some(fork) = self.forks.get(&cursor) {
+            branch.push(cursor);
+            cursor = fork
+                .block
+                .prev
+                .expect("branches fork off the canonical chain");
+        }
+        let fork_point = self.heights[&cursor] + 1;
+        if let Some(storage) = self.storage.as_mut() {
+            storage.truncate(fork_point)?;
+            branch
+                .iter()
+                .rev()
+                .try_for_each(|hash| storage.append(&self.forks[hash].block))?;
+        }
+        let mut reorg = Reorg::default();
+        let blocks = self.blocks.split_off(fork_point);
+        let hashes = self.hashes.split_off(fork_point);
+        let work = self.work.split_off(fork_point);
+        let disconnected = blocks.into_iter().zip(hashes).zip(work).enumerate();
+        for (i, ((block, hash), work)) in disconnected.rev() {
+            self.heights.remove(&hash);
+            self.ledger.disconnect();
+            reorg.disconnected.push(block.clone());
+            self.forks.insert(
+                hash,
+                Fork {
+                    block,
+                    height: fork_point + i,
+                    work,
+                },
+            );
+        }
+        for hash in branch.into_iter().rev() {
+            let Fork { block, .. } = self.forks.remove(&hash).expect("branch block");
+            reorg.connected.push(block.clone());
+            self.push(block);
+        }
+        Ok(reorg)
+    }
+
+    /// Height and cumulative work of a block on any branch.
+    pub(crate) fn locate(&self, hash: &BlockHash) -> Option<(usize, u128)> {
+        match self.heights.get(hash) {
+            Some(&height) => Some((height, self.work[height])),
+            None => self.forks.get(hash).map(|fork| (fork.height, fork.work)),
+        }
+    }
+
+    /// The block at `height` on the branch ending at `tip`.
+    pub(crate) fn ancestor(&self, tip: Option<BlockHash>, height: usize) -> Option<&Block> {
+        let mut cursor = tip?;
+        loop {
+            if let Some(&tip_height) = self.heights.get(&cursor) {
+                return self.blocks.get(height).filter(|_| height <= tip_height);
+            }
+            let fork = self.forks.get(&cursor)?;
+            if fork.height <= height {
+                return (fork.height == height).then_some(&fork.block);
+            }
+            cursor = fork.block.prev?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{Address, Mempool, OutPoint, SigningKey, Transaction};
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// A chain of its own sharing the first `shared` blocks of `chain`,
+    /// whose later blocks pay another miner.
+    fn branch(chain: &Blockchain, shared: usize) -> Blockchain {
+        let mut branch = Blockchain::new(4).with_miner(Address::of(&key(2)));
+        for block in chain.iter().take(shared) {
+            branch.accept(block.clone()).unwrap();
+        }
+        branch
+    }
+
+    #[test]
+    fn tie_keeps_the_current_tip() {
+        let mut chain = Blockchain::new(4).with_miner(Address::of(&key(1)));
+        chain.add(Vec::new()).unwrap();
+        chain.add(Vec::new()).unwrap();
+        let tip = chain.hash_at(1);
+        let mut rival = branch(&chain, 1);
+        rival.add(Vec::new()).unwrap();
+
+        let block = rival.tip().unwrap().clone();
+        let hash = chain.hash_block(&block);
+        assert_eq!(chain.accept(block.clone()).unwrap(), Reorg::default());
+        assert_eq!((chain.len(), chain.hash_at(1)), (2, tip));
+        assert!(chain.contains(&hash));
+        assert_eq!(chain.height_of(&hash), None);
+        assert!(matches!(
+            chain.accept(block).unwrap_err().downcast(),
+            Ok(BlockError::Known(known)) if known == hash
+        ));
+    }
+
+    #[test]
+    fn heavier_branch_reorgs_and_rewrites_the_file() {
+        let path = std::env::temp_dir().join(format!("pz1-{}-reorg.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut chain = Blockchain::new(4).with_miner(Address::of(&key(1)));
+        chain.save(&path).unwrap();
+        (0..3).for_each(|_| chain.add(Vec::new()).unwrap());
+        let mut rival = branch(&chain, 1);
+        (0..3).for_each(|_| rival.add(Vec::new()).unwrap());
+
+        let blocks: Vec<_> = rival.iter().cloned().collect();
+        assert_eq!(chain.accept(blocks[1].clone()).unwrap(), Reorg::default());
+        assert_eq!(chain.accept(blocks[2].clone()).unwrap(), Reorg::default());
+        let disconnected: Vec<_> = chain.iter().skip(1).rev().cloned().collect();
+        let reorg = chain.accept(blocks[3].clone()).unwrap();
+        assert_eq!(reorg.disconnected, disconnected);
+        assert_eq!(reorg.connected, blocks[1..]);
+
+        assert_eq!(chain.tip(), rival.tip());
+        assert_eq!(chain.chain_work(), rival.chain_work());
+        for miner in [key(1), key(2)] {
+            let miner = Address::of(&miner);
+            assert_eq!(chain.accounts().get(&miner), rival.accounts().get(&miner));
+        }
+        assert!(chain.verify_chain().is_ok());
+        assert_eq!(fs::read(&path).unwrap(), rival.to_bytes());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reorg_returns_transactions_to_the_mempool() {
+        let sender = key(1);
+        let mut chain = Blockchain::new(4).with_miner(Address::of(&sender));
+        chain.add(Vec::new()).unwrap();
+        let coinbase = &chain.tip().unwrap().transactions()[0];
+        let transaction = Transaction {
+            sender: Address::of(&sender),
+            recipient: Address::of(&key(3)),
+            amount: 1,
+            fee: 0,
+            nonce: 0,
+            inputs: vec![OutPoint {
+                tx: coinbase.id(),
+                index: 0,
+            }],
+            memo: String::new(),
+            signature: None,
+        }
+        .sign(&sender);
+        chain.add(vec![transaction.clone()]).unwrap();
+        let mut rival = branch(&chain, 1);
+        (0..2).for_each(|_| rival.add(Vec::new()).unwrap());
+
+        let mut mempool = Mempool::new();
+        let mut reorg = Reorg::default();
+        for block in rival.iter().skip(1) {
+            reorg = chain.accept(block.clone()).unwrap();
+        }
+        assert_eq!(reorg.disconnected.len(), 1);
+        mempool.update(&reorg, chain.accounts());
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&transaction.id()));
+
+        // Once a block of the new branch includes it, it is no longer pending.
+        chain.add(mempool.assemble(usize::MAX, &chain)).unwrap();
+        let reorg = Reorg {
+            disconnected: Vec::new(),
+            connected: vec![chain.tip().unwrap().clone()],
+        };
+        mempool.update(&reorg, chain.accounts());
+        assert!(mempool.is_empty());
+    }
+}
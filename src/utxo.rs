@@ -0,0 +1,370 @@
+use core::{fmt, str::FromStr};
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    hash::{self, Hex},
+    Address, Block, Blockchain, Reorg, Transaction,
+};
+
+/// Names a transaction output by the transaction's id and the output's index.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OutPoint {
+    pub tx: [u8; 32],
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(transaction: &Transaction, index: u32) -> Self {
+        Self {
+            tx: transaction.id(),
+            index,
+        }
+    }
+}
+
+/// The transaction id in hex, a colon, then the index.
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", Hex(&self.tx), self.index)
+    }
+}
+
+impl fmt::Debug for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OutPoint({self})")
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (tx, index) = s.split_once(':').context("expected <tx id>:<index>")?;
+        Ok(Self {
+            tx: hash::parse_hex(tx)?,
+            index: index.parse().context("invalid output index")?,
+        })
+    }
+}
+
+/// Serialized as the string it displays as.
+impl Serialize for OutPoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for OutPoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Coins held by an output and who may spend them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Output {
+    pub owner: Address,
+    pub amount: u64,
+}
+
+/// Why [`UtxoSet::connect`] or [`UtxoSet::update`] rejected a block, naming
+/// the offending transaction by its index in the block.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum UtxoError {
+    #[error("transaction {index} spends {input}, which is unknown or already spent")]
+    Missing { index: usize, input: OutPoint },
+    #[error("transaction {index} spends {input}, which belongs to {owner}")]
+    NotOwner {
+        index: usize,
+        input: OutPoint,
+        owner: Address,
+    },
+    #[error("transaction {index} has inputs of {available}, short of the {needed} it pays out")]
+    Insufficient {
+        index: usize,
+        available: u64,
+        needed: u64,
+    },
+    #[error("transaction {index}'s amounts overflow")]
+    Overflow { index: usize },
+    #[error("transaction {index} spends no outputs")]
+    NoInputs { index: usize },
+    #[error("cannot disconnect {disconnected} blocks with only {connected} connected")]
+    NotConnected {
+        disconnected: usize,
+        connected: usize,
+    },
+}
+
+/// The outputs left unspent by the blocks connected so far, along with what
+/// each of those blocks changed, so they can be disconnected again tip first.
+#[derive(Clone, Debug, Default)]
+pub struct UtxoSet {
+    unspent: HashMap<OutPoint, Output>,
+    /// Changes made by each connected block, lowest first, each block's in
+    /// the order they were made.
+    undo: Vec<Vec<Change>>,
+}
+
+#[derive(Clone, Debug)]
+enum Change {
+    Spent(OutPoint, Output),
+    Created(OutPoint),
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of unspent outputs.
+    pub fn len(&self) -> usize {
+        self.unspent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unspent.is_empty()
+    }
+
+    /// Number of blocks connected.
+    pub fn blocks(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Output> {
+        self.unspent.get(outpoint)
+    }
+
+    /// Every unspent output, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &Output)> {
+        self.unspent.iter()
+    }
+
+    /// The unspent outputs `owner` may spend, in no particular order.
+    pub fn owned_by(&self, owner: Address) -> impl Iterator<Item = (&OutPoint, &Output)> {
+        self.iter().filter(move |(_, output)| output.owner == owner)
+    }
+
+    pub fn balance(&self, owner: Address) -> u64 {
+        self.owned_by(owner).map(|(_, output)| output.amount).sum()
+    }
+
+    /// Applies the block's transactions in order, so each may spend outputs
+    /// created earlier in the block, the coinbase's included. If one is
+    /// rejected the set is left as it was.
+    pub fn connect(&mut self, block: &Block) -> Result<(), UtxoError> {
+        self.open_block();
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if let Err(error) = self.apply(index, transaction) {
+                self.disconnect();
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts recording the changes of the next block's transactions.
+    pub(crate) fn open_block(&mut self) {
+        self.undo.push(Vec::new());
+    }
+
+    /// Applies the transaction at `index` in the block opened last, or
+    /// changes nothing if it's rejected.
+    pub(crate) fn apply(
+        &mut self,
+        index: usize,
+        transaction: &Transaction,
+    ) -> Result<(), UtxoError> {
+        let mut changes = Vec::new();
+        match self.transfer(index, transaction, &mut changes) {
+            Ok(()) => {
+                self.undo
+                    .last_mut()
+                    .expect("applying to an open block")
+                    .extend(changes);
+                Ok(())
+            }
+            Err(error) => {
+                self.revert(changes);
+                Err(error)
+            }
+        }
+    }
+
+    /// Reverts the most recently connected block, returning whether there
+    /// was one.
+    pub fn disconnect(&mut self) -> bool {
+        match self.undo.pop() {
+            Some(changes) => {
+                self.revert(changes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A copy of the set `blocks` blocks below this one, without the
+    /// history to disconnect any further.
+    ///
+    /// Panics if fewer than `blocks` blocks are connected.
+    pub(crate) fn rewound(&self, blocks: usize) -> Self {
+        assert!(blocks <= self.blocks(), "rewound past genesis");
+        let mut set = Self {
+            unspent: self.unspent.clone(),
+            undo: Vec::new(),
+        };
+        self.undo
+            .iter()
+            .rev()
+            .take(blocks)
+            .for_each(|changes| set.revert(changes.clone()));
+        set
+    }
+
+    /// Follows the chain through a reorganization. A reorganization
+    /// disconnecting more blocks than were connected changes nothing, while
+    /// if a connected block is rejected, the set stays at its parent.
+    pub fn update(&mut self, reorg: &Reorg) -> Result<(), UtxoError> {
+        if reorg.disconnected.len() > self.blocks() {
+            return Err(UtxoError::NotConnected {
+                disconnected: reorg.disconnected.len(),
+                connected: self.blocks(),
+            });
+        }
+        for _ in &reorg.disconnected {
+            self.disconnect();
+        }
+        reorg
+            .connected
+            .iter()
+            .try_for_each(|block| self.connect(block))
+    }
+
+    fn transfer(
+        &mut self,
+        index: usize,
+        transaction: &Transaction,
+        changes: &mut Vec<Change>,
+    ) -> Result<(), UtxoError> {
+        let change = match transaction.is_coinbase() {
+            true => 0,
+            false => self.spend(index, transaction, changes)?,
+        };
+        let tx = transaction.id();
+        let outputs = [
+            (transaction.recipient, transaction.amount),
+            (transaction.sender, change),
+        ];
+        for (output_index, (owner, amount)) in (0..).zip(outputs) {
+            if amount > 0 {
+                let outpoint = OutPoint {
+                    tx,
+                    index: output_index,
+                };
+                self.unspent.insert(outpoint, Output { owner, amount });
+                changes.push(Change::Created(outpoint));
+            }
+        }
+        Ok(())
+    }
+
+    /// Spends the transaction's inputs, returning the change left over
+    /// after its amount and fee.
+    fn spend(
+        &mut self,
+        index: usize,
+        transaction: &Transaction,
+        changes: &mut Vec<Change>,
+    ) -> Result<u64, UtxoError> {
+        if transaction.inputs.is_empty() {
+            return Err(UtxoError::NoInputs { index });
+        }
+        let mut available = 0u64;
+        for input in &transaction.inputs {
+            let output = self.unspent.remove(input).ok_or(UtxoError::Missing {
+                index,
+                input: *input,
+            })?;
+            changes.push(Change::Spent(*input, output));
+            if output.owner != transaction.sender {
+                return Err(UtxoError::NotOwner {
+                    index,
+                    input: *input,
+                    owner: output.owner,
+                });
+            }
+            available = available
+                .checked_add(output.amount)
+                .ok_or(UtxoError::Overflow { index })?;
+        }
+        let needed = transaction
+            .amount
+            .checked_add(transaction.fee)
+            .ok_or(UtxoError::Overflow { index })?;
+        available
+            .checked_sub(needed)
+            .ok_or(UtxoError::Insufficient {
+                index,
+                available,
+                needed,
+            })
+    }
+
+    fn revert(&mut self, changes: Vec<Change>) {
+        for change in changes.into_iter().rev() {
+            match change {
+                Change::Spent(outpoint, output) => {
+                    self.unspent.insert(outpoint, output);
+                }
+                Change::Created(outpoint) => {
+                    self.unspent.remove(&outpoint);
+                }
+            }
+        }
+    }
+}
+
+impl Blockchain {
+    /// Outputs left unspent as of the canonical tip, of which there are none
+    /// on chains following [`StateModel::Accounts`](crate::StateModel::Accounts).
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.ledger.utxos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_rejects_disconnecting_unconnected_blocks() {
+        let mut blockchain = Blockchain::new(4);
+        blockchain.add(Vec::new()).unwrap();
+        let mut utxos = UtxoSet::new();
+        let reorg = Reorg {
+            disconnected: vec![blockchain.tip().unwrap().clone()],
+            connected: Vec::new(),
+        };
+        assert_eq!(
+            utxos.update(&reorg),
+            Err(UtxoError::NotConnected {
+                disconnected: 1,
+                connected: 0
+            })
+        );
+
+        utxos
+            .update(&Reorg {
+                disconnected: Vec::new(),
+                connected: reorg.disconnected.clone(),
+            })
+            .unwrap();
+        assert_eq!(utxos.update(&reorg), Ok(()));
+        assert_eq!(utxos.blocks(), 0);
+    }
+}
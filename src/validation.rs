@@ -0,0 +1,161 @@
+use core::fmt;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    pow::Target, transaction::TransactionError, AccountError, BlockHash, CoinbaseError, UtxoError,
+};
+
+/// Why `Blockchain::verify_chain` rejected a chain, naming the offending
+/// block by its height and hash.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ValidationError {
+    /// The block's link to its parent doesn't match the parent's hash.
+    #[error(
+        "hash mismatch at height {height} ({hash}): specified {actual:?} when expected {expected}"
+    )]
+    HashMismatch {
+        height: usize,
+        hash: BlockHash,
+        expected: BlockHash,
+        actual: Option<BlockHash>,
+    },
+    /// The first block links to a parent.
+    #[error("bad genesis block ({hash}): links to parent {prev}")]
+    BadGenesis { hash: BlockHash, prev: BlockHash },
+    #[error("unsupported version at height {height} ({hash}): {version}")]
+    BadVersion {
+        height: usize,
+        hash: BlockHash,
+        version: u32,
+    },
+    #[error("insufficient work at height {height} ({hash}): above target {target:?}")]
+    InsufficientWork {
+        height: usize,
+        hash: BlockHash,
+        target: Target,
+    },
+    #[error("unexpected bits at height {height} ({hash}): specified {actual:#010x} when expected {expected:#010x}")]
+    BadTarget {
+        height: usize,
+        hash: BlockHash,
+        expected: u32,
+        actual: u32,
+    },
+    /// The block claims to be older than its parent.
+    #[error(
+        "bad timestamp at height {height} ({hash}): {timestamp} is before its parent's {parent}"
+    )]
+    BadTimestamp {
+        height: usize,
+        hash: BlockHash,
+        timestamp: DateTime<Utc>,
+        parent: DateTime<Utc>,
+    },
+    #[error("bad signature on transaction {index} at height {height} ({hash}): {error}")]
+    BadSignature {
+        height: usize,
+        hash: BlockHash,
+        index: usize,
+        error: TransactionError,
+    },
+    #[error("invalid transaction {index} at height {height} ({hash}): {error}")]
+    BadTransaction {
+        height: usize,
+        hash: BlockHash,
+        index: usize,
+        error: TransactionError,
+    },
+    #[error("bad coinbase at height {height} ({hash}): {error}")]
+    BadCoinbase {
+        height: usize,
+        hash: BlockHash,
+        error: CoinbaseError,
+    },
+    /// The sender can't pay for the transaction or reused a nonce.
+    #[error("rejected transfer {index} at height {height} ({hash}): {error}")]
+    BadTransfer {
+        height: usize,
+        hash: BlockHash,
+        index: usize,
+        error: AccountError,
+    },
+    /// A transaction spends outputs that aren't there or aren't the
+    /// sender's, or more than they hold.
+    #[error("rejected spend at height {height} ({hash}): {error}")]
+    BadSpend {
+        height: usize,
+        hash: BlockHash,
+        error: UtxoError,
+    },
+}
+
+impl ValidationError {
+    pub fn height(&self) -> usize {
+        match self {
+            Self::BadGenesis { .. } => 0,
+            Self::HashMismatch { height, .. }
+            | Self::BadVersion { height, .. }
+            | Self::InsufficientWork { height, .. }
+            | Self::BadTarget { height, .. }
+            | Self::BadTimestamp { height, .. }
+            | Self::BadSignature { height, .. }
+            | Self::BadCoinbase { height, .. }
+            | Self::BadTransaction { height, .. }
+            | Self::BadTransfer { height, .. }
+            | Self::BadSpend { height, .. } => *height,
+        }
+    }
+
+    pub fn hash(&self) -> &BlockHash {
+        match self {
+            Self::HashMismatch { hash, .. }
+            | Self::BadGenesis { hash, .. }
+            | Self::BadVersion { hash, .. }
+            | Self::InsufficientWork { hash, .. }
+            | Self::BadTarget { hash, .. }
+            | Self::BadTimestamp { hash, .. }
+            | Self::BadSignature { hash, .. }
+            | Self::BadCoinbase { hash, .. }
+            | Self::BadTransaction { hash, .. }
+            | Self::BadTransfer { hash, .. }
+            | Self::BadSpend { hash, .. } => hash,
+        }
+    }
+}
+
+/// Every fault found in a chain, in height order, and how much of it is
+/// still usable.
+#[derive(Clone, Debug)]
+pub struct ValidationReport {
+    pub len: usize,
+    /// Number of blocks, counting from genesis, below the first fault.
+    pub valid_prefix: usize,
+    /// Hash of the last block in the valid prefix.
+    pub valid_tip: Option<BlockHash>,
+    pub faults: Vec<ValidationError>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.faults.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} fault(s); longest valid prefix is {} of {} blocks",
+            self.faults.len(),
+            self.valid_prefix,
+            self.len
+        )?;
+        if let Some(tip) = &self.valid_tip {
+            write!(f, ", ending at {tip}")?;
+        }
+        self.faults
+            .iter()
+            .try_for_each(|fault| write!(f, "\n  {fault}"))
+    }
+}
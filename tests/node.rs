@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::Receiver,
+    thread,
+    time::{Duration, Instant},
+};
+
+use pz1::{
+    codec,
+    node::{Event, PROTOCOL_VERSION},
+    pow::Target,
+    Block, BlockHash, Blockchain, Header, Node, ValidationError,
+};
+
+const DIFFICULTY: u32 = 8;
+
+fn node() -> (Node, SocketAddr) {
+    let node = Node::new(Blockchain::new(DIFFICULTY));
+    let addr = node.listen("127.0.0.1:0").unwrap();
+    (node, addr)
+}
+
+fn tip(node: &Node) -> Option<BlockHash> {
+    let blockchain = node.blockchain();
+    blockchain.hash_at(blockchain.len().checked_sub(1)?)
+}
+
+fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Completes a handshake with a node over `stream` as a peer with no chain.
+fn handshake(mut stream: TcpStream) -> TcpStream {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(20)))
+        .unwrap();
+    let mut version = vec![0];
+    version.extend(PROTOCOL_VERSION.to_le_bytes());
+    version.push(0);
+    version.extend(0u64.to_le_bytes());
+    stream.write_all(&codec::encode_record(&version)).unwrap();
+    stream.write_all(&codec::encode_record(&[1])).unwrap();
+    let (theirs, verack) = (
+        codec::read_record(&mut stream, 1 << 20).unwrap(),
+        codec::read_record(&mut stream, 1 << 20).unwrap(),
+    );
+    assert_eq!((theirs[0], verack), (0, vec![1]));
+    stream
+}
+
+/// Waits for the node to drop the peer on `stream`, returning why.
+fn dropped(node: &Node, events: &Receiver<Event>, mut stream: TcpStream) -> anyhow::Error {
+    while codec::read_record(&mut stream, 1 << 20).is_ok() {}
+    wait_until("the peer is dropped", || node.peers().is_empty());
+    events
+        .try_iter()
+        .find_map(|event| match event {
+            Event::Disconnected { error, .. } => error,
+            _ => None,
+        })
+        .expect("the peer was dropped for an error")
+}
+
+fn header_bytes(header: &Header) -> Vec<u8> {
+    let mut bytes = header.version().to_le_bytes().to_vec();
+    match header.prev_hash() {
+        Some(prev) => {
+            bytes.push(1);
+            bytes.extend(prev.0);
+        }
+        None => bytes.push(0),
+    }
+    bytes.extend(
+        header
+            .timestamp()
+            .timestamp_nanos_opt()
+            .unwrap()
+            .to_le_bytes(),
+    );
+    bytes.extend(header.merkle_root());
+    bytes.extend(header.bits().to_le_bytes());
+    bytes.extend(header.nonce().to_le_bytes());
+    bytes
+}
+
+fn headers_message(header: &[u8]) -> Vec<u8> {
+    let mut message = vec![6];
+    message.extend(1u32.to_le_bytes());
+    message.extend(header);
+    codec::encode_record(&message)
+}
+
+#[test]
+fn nodes_converge_on_mined_blocks() {
+    let (a, a_addr) = node();
+    let (b, b_addr) = node();
+    let (c, _) = node();
+    b.connect(a_addr).unwrap();
+    c.connect(b_addr).unwrap();
+    wait_until("peers connect", || b.peers().len() == 2);
+
+    for _ in 0..3 {
+        a.mine().unwrap();
+    }
+    wait_until("b and c follow a", || {
+        tip(&a).is_some() && tip(&b) == tip(&a) && tip(&c) == tip(&a)
+    });
+
+    let hash = c.mine().unwrap();
+    wait_until("a and b follow c", || {
+        tip(&a) == Some(hash) && tip(&b) == Some(hash)
+    });
+    assert_eq!(a.blockchain().len(), 4);
+    assert!(c.blockchain().verify_chain().is_ok());
+}
+
+#[test]
+fn peer_sending_a_corrupted_block_is_dropped() {
+    let (node, addr) = node();
+    node.mine().unwrap();
+    let genesis = node.blockchain().tip().unwrap().clone();
+    let events = node.events();
+
+    let mut stream = handshake(TcpStream::connect(addr).unwrap());
+    wait_until("the peer registers", || node.peers().len() == 1);
+
+    // Mined properly, but with a coinbase claiming more than it may.
+    let mut mirror = Blockchain::new(DIFFICULTY);
+    mirror.accept(genesis).unwrap();
+    let mut block = serde_json::to_value(mirror.template(Vec::new()).unwrap()).unwrap();
+    block["transactions"][0]["amount"] = u64::MAX.into();
+    let block: Block = serde_json::from_value(block).unwrap();
+    let block = block.mine(mirror.mining_threads());
+    let mut message = vec![5];
+    message.extend(block.encode());
+    stream.write_all(&codec::encode_record(&message)).unwrap();
+
+    let error = dropped(&node, &events, stream);
+    assert_eq!(node.blockchain().len(), 1);
+    assert!(error.chain().any(|cause| cause.is::<ValidationError>()));
+}
+
+#[test]
+fn peer_sending_a_bad_header_is_dropped() {
+    let (node, addr) = node();
+    node.mine().unwrap();
+    let genesis = node.blockchain().tip().unwrap().clone();
+    let events = node.events();
+    let mut mirror = Blockchain::new(DIFFICULTY);
+    mirror.accept(genesis).unwrap();
+    let block = mirror.template(Vec::new()).unwrap();
+    let mined = block.clone().mine(mirror.mining_threads()).header();
+    let target = Target::from_compact(mined.bits()).unwrap();
+
+    // Mined onto a parent nobody has.
+    let mut orphan = header_bytes(&mined);
+    orphan[5..37].copy_from_slice(&[9; 32]);
+    let mut stream = handshake(TcpStream::connect(addr).unwrap());
+    stream.write_all(&headers_message(&orphan)).unwrap();
+    let error = dropped(&node, &events, stream);
+    assert!(format!("{error:#}").contains("doesn't connect"));
+
+    // Extends the chain, but with a nonce that misses the target.
+    let nonce = (0..)
+        .find(|&nonce| {
+            let mut block = serde_json::to_value(&block).unwrap();
+            block["nonce"] = nonce.into();
+            let block: Block = serde_json::from_value(block).unwrap();
+            !target.is_met_by(&block.header().hash())
+        })
+        .unwrap();
+    let mut unmined = header_bytes(&mined);
+    let len = unmined.len();
+    unmined[len - 8..].copy_from_slice(&u64::to_le_bytes(nonce));
+    let mut stream = handshake(TcpStream::connect(addr).unwrap());
+    stream.write_all(&headers_message(&unmined)).unwrap();
+    let error = dropped(&node, &events, stream);
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(ValidationError::InsufficientWork { height: 1, .. })
+    ));
+    assert_eq!(node.blockchain().len(), 1);
+}
+
+#[test]
+fn restarted_node_resumes_from_its_tip() {
+    let (a, a_addr) = node();
+    for _ in 0..6 {
+        a.mine().unwrap();
+    }
+    // As if the node had stopped after persisting half the chain.
+    let path = std::env::temp_dir().join(format!("pz1-{}-restart.bin", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut blockchain = Blockchain::new(DIFFICULTY);
+    blockchain.save(&path).unwrap();
+    for block in a.blockchain().iter().take(3) {
+        blockchain.accept(block.clone()).unwrap();
+    }
+    let persisted_tip = blockchain.hash_at(2);
+    drop(blockchain);
+
+    let b = Node::new(Blockchain::new(DIFFICULTY).open(&path).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let probe = listener.local_addr().unwrap();
+    let connecting = {
+        let b = b.clone();
+        thread::spawn(move || b.connect(probe))
+    };
+    let mut stream = handshake(listener.accept().unwrap().0);
+    connecting.join().unwrap().unwrap();
+    let get_headers = codec::read_record(&mut stream, 1 << 20).unwrap();
+    assert_eq!(get_headers[0], 3, "asks for headers first");
+    assert_eq!(
+        get_headers[5..37],
+        persisted_tip.unwrap().0,
+        "starting after its tip"
+    );
+    drop(stream);
+
+    b.connect(a_addr).unwrap();
+    wait_until("b catches up", || tip(&b) == tip(&a));
+    drop(b);
+    let reopened = Blockchain::new(DIFFICULTY).open(&path).unwrap();
+    assert_eq!(reopened.len(), 6);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn dropped_node_stops_listening_and_serving() {
+    let (a, a_addr) = node();
+    let (b, _) = node();
+    b.connect(a_addr).unwrap();
+    wait_until("peers connect", || a.peers().len() == 1);
+
+    drop(a);
+    wait_until("the listener closes", || {
+        TcpStream::connect(a_addr).is_err()
+    });
+    wait_until("b loses its peer", || b.peers().is_empty());
+}